@@ -0,0 +1,58 @@
+//! Subcommands that do something and exit, rather than running the server.
+
+use clap::ArgMatches;
+use failure;
+
+use homepage_data::backup_store;
+use homepage_data::config;
+
+/// `encasa backups list|show|restore`
+pub fn backups(matches: &ArgMatches) -> Result<(), failure::Error> {
+    let store = backup_store();
+    match matches.subcommand() {
+        ("list", Some(m)) => {
+            let backups = match m.value_of("file") {
+                Some(file) => store.list_for(&::shellexpand::tilde(file))?,
+                None => store.list()?,
+            };
+            for backup in backups {
+                let created = ::chrono::DateTime::<::chrono::Local>::from(backup.created);
+                println!("{}  {}  {:<10}  {}", backup.id, created.format("%Y-%m-%d %H:%M:%S"),
+                         backup.operation.to_string(), backup.source);
+            }
+        },
+        ("show", Some(m)) => {
+            let backup = store.get(m.value_of("id").unwrap())?;
+            print!("{}", store.contents(&backup)?);
+        },
+        ("restore", Some(m)) => {
+            let backup = store.restore(m.value_of("id").unwrap())?;
+            println!("restored {} from {}", backup.source, backup.id);
+        },
+        _ => println!("{}", matches.usage()),
+    }
+    Ok(())
+}
+
+/// `encasa check-config`
+pub fn check_config() -> Result<(), failure::Error> {
+    let path = config::path()?;
+    let contents = ::std::fs::read_to_string(&path)?;
+    let problems = config::check(&contents);
+    if problems.is_empty() {
+        println!("{}: ok", path.display());
+        return Ok(());
+    }
+
+    for problem in &problems {
+        match problem.line {
+            0 => print!("{}: ", path.display()),
+            line => print!("{}:{}: ", path.display(), line),
+        }
+        if !problem.field.is_empty() {
+            print!("{}: ", problem.field);
+        }
+        println!("{}", problem.message);
+    }
+    Err(format_err!("found {} problem(s) in {}", problems.len(), path.display()))
+}
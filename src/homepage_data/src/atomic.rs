@@ -0,0 +1,167 @@
+//! Changing todo files without losing data.
+//!
+//! Every change to a todo file goes through a `LockedFile`. It takes an
+//! advisory lock, reads the file, and when it's written back it checks that
+//! nothing else (Dropbox, an editor) has changed the file since it was read.
+//! The new contents are written to a temporary file next to the original,
+//! synced to disk and renamed over it, so a crash part way through leaves
+//! either the old file or the new one, never half of each.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use failure::ResultExt;
+use fs2::FileExt;
+
+use FileState;
+
+/// Lock files live here rather than next to the files they lock, so they
+/// don't get synced around, and so they outlive the renames that replace
+/// the files themselves.
+static LOCKS_DIR: &str = "~/.homepage/locks";
+
+/// The state of the file at `path`, or `None` if there isn't one.
+pub fn file_state(path: &str) -> Result<Option<FileState>, ::failure::Error> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some(FileState::from(&metadata))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn lock_path(path: &str) -> String {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = ::std::collections::hash_map::DefaultHasher::new();
+    path.hash(&mut hasher);
+    format!("{}/{:016x}.lock", ::shellexpand::tilde(LOCKS_DIR), hasher.finish())
+}
+
+/// A file that we hold the lock for, with what it contained when we read it.
+pub struct LockedFile {
+    pub path: String,
+    /// The file's contents when it was read; empty if it didn't exist.
+    pub contents: String,
+    /// The file's state when it was read; `None` if it didn't exist.
+    pub state: Option<FileState>,
+    lock: File,
+}
+
+impl LockedFile {
+    /// Locks `path`, waiting for anyone else who holds it, and reads it.
+    pub fn open(path: &str) -> Result<LockedFile, ::failure::Error> {
+        let lock_path = lock_path(path);
+        if let Some(dir) = Path::new(&lock_path).parent() {
+            fs::create_dir_all(dir).context(format!("could not create lock directory {}", dir.display()))?;
+        }
+        let lock = OpenOptions::new().create(true).write(true).truncate(false).open(&lock_path)
+            .context(format!("could not open lock file {}", lock_path))?;
+        lock.lock_exclusive().context(format!("could not lock {}", path))?;
+
+        let state = file_state(path)?;
+        let contents = match state {
+            Some(_) => ::get_file_contents(path)?,
+            None => String::new(),
+        };
+
+        Ok(LockedFile { path: path.to_string(), contents, state, lock })
+    }
+
+    /// Replaces the file with `contents`, unless it's been changed since we
+    /// read it.
+    pub fn write(&mut self, contents: &str) -> Result<(), ::failure::Error> {
+        self.check_unchanged()?;
+        write_atomically(&self.path, contents.as_bytes())?;
+        ::store::mark_stale();
+        self.state = file_state(&self.path)?;
+        self.contents = contents.to_string();
+        Ok(())
+    }
+
+    /// Deletes the file, unless it's been changed since we read it.
+    pub fn remove(&mut self) -> Result<(), ::failure::Error> {
+        self.check_unchanged()?;
+        if self.state.is_some() {
+            fs::remove_file(&self.path)?;
+            ::store::mark_stale();
+        }
+        self.state = None;
+        self.contents.clear();
+        Ok(())
+    }
+
+    /// Fails if something else changed the file since we read it, for
+    /// checking several files before writing any of them.
+    pub fn check_unchanged(&self) -> Result<(), ::failure::Error> {
+        if file_state(&self.path)? != self.state {
+            return Err(format_err!("{} was changed by something else while we were editing it, so it wasn't overwritten", self.path));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LockedFile {
+    fn drop(&mut self) {
+        // closing the file would release the lock anyway
+        let _ = self.lock.unlock();
+    }
+}
+
+/// Replaces the file at `path` with `contents` by writing them to a
+/// temporary file in the same directory and renaming it into place. If
+/// `path` is a symlink, the file it points to is replaced instead.
+pub fn write_atomically(path: &str, contents: &[u8]) -> Result<(), ::failure::Error> {
+    let target = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let mut temp = ::tempfile::NamedTempFile::new_in(&dir)
+        .context(format!("could not create a temporary file in {}", dir.display()))?;
+    temp.write_all(contents)?;
+    if let Ok(metadata) = fs::metadata(&target) {
+        fs::set_permissions(temp.path(), metadata.permissions())?;
+    }
+    temp.as_file().sync_all()?;
+    temp.persist(&target)
+        .map_err(|e| format_err!("could not replace {}: {}", path, e.error))?;
+
+    // make the rename itself durable; directories can't be opened for
+    // syncing everywhere, so this is best effort
+    if let Ok(dir) = File::open(&dir) {
+        let _ = dir.sync_all();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_to_clobber_concurrent_changes() {
+        let dir = ::tempfile::tempdir().unwrap();
+        let path = dir.path().join("todo.txt");
+        let path = path.to_str().unwrap();
+        File::create(path).unwrap().write_all(b"one\n").unwrap();
+
+        let mut locked = LockedFile::open(path).unwrap();
+        assert_eq!(locked.contents, "one\n");
+        locked.write("one\ntwo\n").unwrap();
+        assert_eq!(::get_file_contents(path).unwrap(), "one\ntwo\n");
+        drop(locked);
+
+        let mut locked = LockedFile::open(path).unwrap();
+        File::create(path).unwrap().write_all(b"changed elsewhere, and longer\n").unwrap();
+        assert!(locked.write("mine\n").is_err());
+        assert_eq!(::get_file_contents(path).unwrap(), "changed elsewhere, and longer\n");
+
+        let new_path = dir.path().join("done.txt");
+        let mut locked = LockedFile::open(new_path.to_str().unwrap()).unwrap();
+        assert_eq!(locked.state, None);
+        locked.write("x done\n").unwrap();
+        assert_eq!(::get_file_contents(new_path.to_str().unwrap()).unwrap(), "x done\n");
+    }
+}
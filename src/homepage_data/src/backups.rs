@@ -0,0 +1,293 @@
+//! Backups of todo files.
+//!
+//! Before we change a file, what was in it is saved to the backup directory
+//! along with a small YAML file saying where it came from, when, and what we
+//! were about to do to it. Old backups are pruned according to the
+//! `backups:` section of `homepage.yaml`:
+//!
+//! ```yaml
+//! backups:
+//!   keep_per_file: 50
+//!   keep_days: 30
+//! ```
+//!
+//! A backup is removed once it falls outside either limit that's set.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use failure::ResultExt;
+
+use atomic::{self, LockedFile};
+
+/// What we were doing to a file when we backed it up.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Complete,
+    Reopen,
+    Add,
+    Edit,
+    StartTimer,
+    StopTimer,
+    Archive,
+    Restore,
+    Undo,
+    Redo,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Operation::Complete => "complete",
+            Operation::Reopen => "reopen",
+            Operation::Add => "add",
+            Operation::Edit => "edit",
+            Operation::StartTimer => "start timer",
+            Operation::StopTimer => "stop timer",
+            Operation::Archive => "archive",
+            Operation::Restore => "restore",
+            Operation::Undo => "undo",
+            Operation::Redo => "redo",
+        })
+    }
+}
+
+fn default_keep_per_file() -> Option<usize> {
+    Some(20)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Retention {
+    /// How many backups to keep for each file.
+    #[serde(default = "default_keep_per_file")] pub keep_per_file: Option<usize>,
+    /// How many days to keep backups for.
+    #[serde(default)] pub keep_days: Option<u64>,
+}
+
+impl Default for Retention {
+    fn default() -> Retention {
+        Retention { keep_per_file: default_keep_per_file(), keep_days: None }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Backup {
+    pub id: String,
+    /// The expanded path of the file that was backed up.
+    pub source: String,
+    pub created: SystemTime,
+    pub operation: Operation,
+    #[serde(default)] pub size: u64,
+}
+
+#[derive(Debug)]
+pub struct BackupNotFound {
+    pub id: String,
+}
+
+impl fmt::Display for BackupNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "no backup with id {}", self.id)
+    }
+}
+
+impl ::failure::Fail for BackupNotFound {}
+
+pub struct Backups {
+    dir: PathBuf,
+    retention: Retention,
+}
+
+impl Backups {
+    pub fn new(dir: &str, retention: Retention) -> Backups {
+        Backups { dir: PathBuf::from(dir), retention }
+    }
+
+    fn contents_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.txt", id))
+    }
+
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.yaml", id))
+    }
+
+    /// A new id, which sorts by time and says which file it's for.
+    fn new_id(&self, source: &str, created: SystemTime) -> String {
+        let stem:String = Path::new(source).file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        let time = ::chrono::DateTime::<::chrono::Local>::from(created).format("%Y%m%dT%H%M%S%.6f");
+
+        let mut id = format!("{}-{}", time, stem);
+        let mut n = 1;
+        while self.info_path(&id).exists() {
+            n += 1;
+            id = format!("{}-{}-{}", time, stem, n);
+        }
+        id
+    }
+
+    /// Saves `contents`, which are about to be replaced by `operation`, as
+    /// a backup of `source`, and prunes old backups.
+    pub fn save(&self, source: &str, contents: &str, operation: Operation) -> Result<Backup, ::failure::Error> {
+        fs::create_dir_all(&self.dir)
+            .context(format!("could not create backup directory {}", self.dir.display()))?;
+
+        let created = SystemTime::now();
+        let backup = Backup {
+            id: self.new_id(source, created),
+            source: source.to_string(),
+            created,
+            operation,
+            size: contents.len() as u64,
+        };
+
+        let contents_path = self.contents_path(&backup.id);
+        File::create(&contents_path)
+            .and_then(|mut f| f.write_all(contents.as_bytes()).and_then(|_| f.sync_all()))
+            .context(format!("could not write backup {}", contents_path.display()))?;
+        // the info goes last, so that a backup is only listed once it's complete
+        let yaml = ::serde_yaml::to_string(&backup)
+            .map_err(|e| format_err!("could not convert backup info to YAML: {}", e))?;
+        atomic::write_atomically(&self.info_path(&backup.id).to_string_lossy(), yaml.as_bytes())?;
+
+        self.prune()?;
+        Ok(backup)
+    }
+
+    /// All backups, newest first.
+    pub fn list(&self) -> Result<Vec<Backup>, ::failure::Error> {
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut backups = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |e| e != "yaml") {
+                continue;
+            }
+            let contents = ::get_file_contents(&path.to_string_lossy())?;
+            match ::serde_yaml::from_str::<Backup>(&contents) {
+                Ok(backup) => backups.push(backup),
+                Err(e) => eprintln!("skipping unreadable backup info {}: {}", path.display(), e),
+            }
+        }
+
+        backups.sort_by(|a, b| b.created.cmp(&a.created).then_with(|| b.id.cmp(&a.id)));
+        Ok(backups)
+    }
+
+    /// The backups of the file at `source`, newest first.
+    pub fn list_for(&self, source: &str) -> Result<Vec<Backup>, ::failure::Error> {
+        Ok(self.list()?.into_iter().filter(|b| b.source == source).collect())
+    }
+
+    pub fn get(&self, id: &str) -> Result<Backup, ::failure::Error> {
+        // ids only ever come from `new_id`, so anything else can't be one
+        let valid = !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+        let path = self.info_path(id);
+        if !valid || !path.exists() {
+            return Err(BackupNotFound { id: id.to_string() }.into());
+        }
+        let contents = ::get_file_contents(&path.to_string_lossy())?;
+        Ok(::serde_yaml::from_str(&contents)
+            .map_err(|e| format_err!("could not read backup info {}: {}", path.display(), e))?)
+    }
+
+    pub fn contents(&self, backup: &Backup) -> Result<String, ::failure::Error> {
+        ::get_file_contents(&self.contents_path(&backup.id).to_string_lossy())
+    }
+
+    /// Puts a backup's contents back in place, first backing up what's there
+    /// now so that the restore can itself be undone.
+    pub fn restore(&self, id: &str) -> Result<Backup, ::failure::Error> {
+        let backup = self.get(id)?;
+        let contents = self.contents(&backup)?;
+
+        let mut file = LockedFile::open(&backup.source)?;
+        self.save(&backup.source, &file.contents, Operation::Restore)?;
+        file.write(&contents)?;
+        Ok(backup)
+    }
+
+    fn remove(&self, backup: &Backup) -> Result<(), ::failure::Error> {
+        fs::remove_file(self.info_path(&backup.id))?;
+        let contents_path = self.contents_path(&backup.id);
+        if contents_path.exists() {
+            fs::remove_file(contents_path)?;
+        }
+        Ok(())
+    }
+
+    /// Removes backups that fall outside the retention limits. Returns how
+    /// many were removed.
+    pub fn prune(&self) -> Result<usize, ::failure::Error> {
+        let max_age = self.retention.keep_days.map(|days| Duration::from_secs(days * 24 * 60 * 60));
+        let mut per_file:HashMap<String, usize> = HashMap::new();
+        let mut removed = 0;
+
+        for backup in self.list()? {
+            let count = {
+                let count = per_file.entry(backup.source.clone()).or_insert(0);
+                *count += 1;
+                *count
+            };
+            let too_many = self.retention.keep_per_file.map_or(false, |keep| count > keep);
+            let too_old = match (max_age, backup.created.elapsed()) {
+                (Some(max_age), Ok(age)) => age > max_age,
+                _ => false,
+            };
+            if too_many || too_old {
+                self.remove(&backup)?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_list_restore_and_prune() {
+        let dir = ::tempfile::tempdir().unwrap();
+        let todo_path = dir.path().join("todo.txt");
+        let todo_path = todo_path.to_str().unwrap();
+        let backups_dir = dir.path().join("backups");
+        let backups = Backups::new(backups_dir.to_str().unwrap(), Retention { keep_per_file: Some(3), keep_days: None });
+
+        File::create(todo_path).unwrap().write_all(b"one\n").unwrap();
+        let first = backups.save(todo_path, "one\n", Operation::Edit).unwrap();
+        assert!(first.id.ends_with("-todo"));
+        File::create(todo_path).unwrap().write_all(b"two\n").unwrap();
+
+        backups.restore(&first.id).unwrap();
+        assert_eq!(::get_file_contents(todo_path).unwrap(), "one\n");
+
+        let list = backups.list_for(todo_path).unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].operation, Operation::Restore);
+        assert_eq!(backups.contents(&list[0]).unwrap(), "two\n");
+
+        for n in 0..5 {
+            backups.save(todo_path, &format!("{}\n", n), Operation::Complete).unwrap();
+        }
+        let list = backups.list().unwrap();
+        assert_eq!(list.len(), 3);
+        assert_eq!(backups.contents(&list[0]).unwrap(), "4\n");
+
+        assert!(backups.get("../../etc/passwd").unwrap_err().downcast::<BackupNotFound>().is_ok());
+    }
+}
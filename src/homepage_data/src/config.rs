@@ -0,0 +1,423 @@
+//! Finding, reading and checking the config file.
+//!
+//! The config file is the first of these that exists:
+//!
+//! 1. the path given with `--config` or `ENCASA_CONFIG`
+//! 2. `$XDG_CONFIG_HOME/encasa/config.yaml` (`~/.config/encasa/config.yaml`)
+//! 3. `encasa/config.yaml` in each of `$XDG_CONFIG_DIRS` (`/etc/xdg`)
+//! 4. `~/homepage.yaml`, where it used to have to be
+//!
+//! Before the config is used it's checked as a whole, so that every problem
+//! with it can be reported at once, each with its line and field, rather
+//! than just the first one serde comes across.
+
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::{Marker, TScalarStyle};
+
+use HomepageMeta;
+
+static LEGACY_PATH: &str = "~/homepage.yaml";
+
+const TOP_LEVEL_KEYS: &[&str] = &["local", "backups", "history", "deadlines"];
+const LOCAL_FILE_KEYS: &[&str] = &["name", "path", "todos", "frequency_goal_seconds",
+    "auto_project", "hide_in_index", "git", "relative_dates", "words_per_day", "open_tasks_max",
+    "kind", "glob", "done_dir", "format", "archive"];
+const RETENTION_KEYS: &[&str] = &["keep_per_file", "keep_days"];
+
+lazy_static! {
+    static ref EXPLICIT_PATH: RwLock<Option<String>> = RwLock::new(None);
+}
+
+/// Uses the config file at `path` instead of looking for one.
+pub fn set_path(path: &str) {
+    *EXPLICIT_PATH.write().unwrap() = Some(::shellexpand::tilde(path).to_string());
+}
+
+/// Where the config file may be, in the order they're tried.
+pub fn candidates() -> Vec<PathBuf> {
+    if let Some(ref path) = *EXPLICIT_PATH.read().unwrap() {
+        return vec![PathBuf::from(path)];
+    }
+
+    let home = PathBuf::from(::shellexpand::tilde("~").to_string());
+    let mut candidates = vec![];
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(ref dir) if !dir.is_empty() => candidates.push(Path::new(dir).join("encasa/config.yaml")),
+        _ => candidates.push(home.join(".config/encasa/config.yaml")),
+    }
+    let dirs = env::var("XDG_CONFIG_DIRS").ok()
+        .and_then(|dirs| if dirs.is_empty() { None } else { Some(dirs) })
+        .unwrap_or_else(|| "/etc/xdg".to_string());
+    for dir in dirs.split(':').filter(|d| !d.is_empty()) {
+        candidates.push(Path::new(dir).join("encasa/config.yaml"));
+    }
+    candidates.push(PathBuf::from(::shellexpand::tilde(LEGACY_PATH).to_string()));
+    candidates
+}
+
+/// The config file to use.
+pub fn path() -> Result<PathBuf, ::failure::Error> {
+    let candidates = candidates();
+    if let Some(path) = candidates.iter().find(|p| p.exists()) {
+        return Ok(path.clone());
+    }
+    let tried:Vec<String> = candidates.iter().map(|p| p.display().to_string()).collect();
+    Err(format_err!("no config file found; tried {}", tried.join(", ")))
+}
+
+/// Something wrong with the config file.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Problem {
+    /// 1-based, or 0 if it's not about any line in particular.
+    pub line: usize,
+    /// Where the problem is, like `local[2].path`.
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.line, self.field.is_empty()) {
+            (0, true) => write!(f, "{}", self.message),
+            (0, false) => write!(f, "{}: {}", self.field, self.message),
+            (line, true) => write!(f, "line {}: {}", line, self.message),
+            (line, false) => write!(f, "line {}: {}: {}", line, self.field, self.message),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    pub path: String,
+    pub problems: Vec<Problem>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "problems with the config file {}:", self.path)?;
+        for problem in &self.problems {
+            write!(f, "\n  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl ::failure::Fail for ConfigError {}
+
+/// Reads and checks the config file.
+pub(crate) fn load() -> Result<HomepageMeta, ::failure::Error> {
+    load_from(&path()?.to_string_lossy())
+}
+
+/// Reads and checks the config file at `path`.
+pub(crate) fn load_from(path: &str) -> Result<HomepageMeta, ::failure::Error> {
+    let contents = ::get_file_contents(path)?;
+    let problems = check(&contents);
+    if !problems.is_empty() {
+        return Err(ConfigError { path: path.to_string(), problems }.into());
+    }
+
+    ::serde_yaml::from_str(&contents).map_err(|e| ConfigError {
+        path: path.to_string(),
+        problems: vec![Problem { line: 0, field: String::new(), message: e.to_string() }],
+    }.into())
+}
+
+/// Checks that the config can be found, read and understood.
+pub fn validate() -> Result<(), ::failure::Error> {
+    load().map(|_| ())
+}
+
+/// A YAML node, with the line it starts on.
+#[derive(Debug)]
+enum Node {
+    Scalar(String, bool),
+    Seq(Vec<Marked>),
+    Map(Vec<(Marked, Marked)>),
+}
+
+#[derive(Debug)]
+struct Marked {
+    node: Node,
+    line: usize,
+}
+
+impl Marked {
+    /// The value of a plain or quoted scalar; `None` for anything else.
+    fn as_str(&self) -> Option<&str> {
+        match self.node {
+            Node::Scalar(ref s, _) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn is_null(&self) -> bool {
+        match self.node {
+            Node::Scalar(ref s, plain) => plain && (s.is_empty() || s == "~" || s == "null"),
+            _ => false,
+        }
+    }
+}
+
+/// Builds a tree of `Marked` nodes from the parser's events, since the
+/// trees serde and yaml-rust build don't remember where anything was.
+#[derive(Default)]
+struct TreeBuilder {
+    /// Open sequences and mappings, each with a key waiting for its value.
+    stack: Vec<(Marked, Option<Marked>)>,
+    root: Option<Marked>,
+}
+
+impl TreeBuilder {
+    fn add(&mut self, node: Marked) {
+        match self.stack.last_mut() {
+            None => {
+                if self.root.is_none() {
+                    self.root = Some(node);
+                }
+            },
+            Some(&mut (ref mut parent, ref mut key)) => match parent.node {
+                Node::Seq(ref mut items) => items.push(node),
+                Node::Map(ref mut entries) => match key.take() {
+                    None => *key = Some(node),
+                    Some(k) => entries.push((k, node)),
+                },
+                Node::Scalar(..) => {},
+            },
+        }
+    }
+}
+
+impl MarkedEventReceiver for TreeBuilder {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        let line = mark.line();
+        match event {
+            Event::Scalar(value, style, ..) => {
+                let plain = style == TScalarStyle::Plain;
+                self.add(Marked { node: Node::Scalar(value, plain), line });
+            },
+            Event::Alias(..) => self.add(Marked { node: Node::Scalar(String::new(), false), line }),
+            Event::SequenceStart(..) => self.stack.push((Marked { node: Node::Seq(vec![]), line }, None)),
+            Event::MappingStart(..) => self.stack.push((Marked { node: Node::Map(vec![]), line }, None)),
+            Event::SequenceEnd | Event::MappingEnd => {
+                if let Some((node, _)) = self.stack.pop() {
+                    self.add(node);
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+struct Checker {
+    problems: Vec<Problem>,
+}
+
+impl Checker {
+    fn problem(&mut self, line: usize, field: &str, message: String) {
+        self.problems.push(Problem { line, field: field.to_string(), message });
+    }
+
+    /// Checks that `node` is a mapping with only `known` keys, and returns
+    /// its entries.
+    fn mapping<'a>(&mut self, node: &'a Marked, field: &str, known: &[&str]) -> Vec<(&'a str, &'a Marked)> {
+        let entries = match node.node {
+            Node::Map(ref entries) => entries,
+            _ => {
+                self.problem(node.line, field, "expected a mapping of keys to values".into());
+                return vec![];
+            },
+        };
+
+        let mut result = vec![];
+        for &(ref key, ref value) in entries {
+            let name = key.as_str().unwrap_or("");
+            if known.contains(&name) {
+                result.push((name, value));
+            } else {
+                self.problem(key.line, field, format!("unknown key `{}`; expected one of {}", name, known.join(", ")));
+            }
+        }
+        result
+    }
+
+    fn string<'a>(&mut self, node: &'a Marked, field: &str) -> Option<&'a str> {
+        match node.node {
+            Node::Scalar(ref s, _) => Some(s),
+            _ => {
+                self.problem(node.line, field, "expected a string".into());
+                None
+            },
+        }
+    }
+
+    fn boolean(&mut self, node: &Marked, field: &str) {
+        match node.node {
+            Node::Scalar(ref s, true) if s == "true" || s == "false" => {},
+            _ => self.problem(node.line, field, format!("expected true or false, not `{}`", node.as_str().unwrap_or("..."))),
+        }
+    }
+
+    fn number(&mut self, node: &Marked, field: &str) {
+        match node.node {
+            Node::Scalar(ref s, true) if s.parse::<u64>().is_ok() => {},
+            _ => self.problem(node.line, field, format!("expected a whole number, not `{}`", node.as_str().unwrap_or("..."))),
+        }
+    }
+
+    fn duration(&mut self, node: &Marked, field: &str) {
+        if let Some(s) = self.string(node, field) {
+            if let Err(e) = ::humantime::parse_duration(s) {
+                self.problem(node.line, field, format!("`{}` isn't a duration like `3days` or `12h`: {}", s, e));
+            }
+        }
+    }
+
+    fn one_of(&mut self, node: &Marked, field: &str, allowed: &[&str]) {
+        if let Some(s) = self.string(node, field) {
+            if !allowed.contains(&s) {
+                self.problem(node.line, field, format!("expected one of {}, not `{}`", allowed.join(", "), s));
+            }
+        }
+    }
+
+    fn glob(&mut self, node: &Marked, field: &str) {
+        if let Some(s) = self.string(node, field) {
+            if let Err(e) = ::glob::Pattern::new(s) {
+                self.problem(node.line, field, format!("`{}` isn't a glob pattern like `*.pdf`: {}", s, e));
+            }
+        }
+    }
+
+    fn local_file(&mut self, node: &Marked, field: &str) {
+        let entries = self.mapping(node, field, LOCAL_FILE_KEYS);
+        if !entries.iter().any(|&(key, _)| key == "path") {
+            self.problem(node.line, field, "missing `path`".into());
+        }
+        for &(key, value) in &entries {
+            let field = format!("{}.{}", field, key);
+            if value.is_null() {
+                continue;
+            }
+            let is_template = |pattern: fn(&str) -> bool| value.as_str().map(pattern).unwrap_or(false);
+            match key {
+                "path" if is_template(::sources::is_glob) => self.glob(value, &field),
+                "todos" | "hide_in_index" | "relative_dates" => self.boolean(value, &field),
+                "frequency_goal_seconds" => self.duration(value, &field),
+                "words_per_day" | "open_tasks_max" => self.number(value, &field),
+                "kind" => self.one_of(value, &field, &["file", "directory"]),
+                "format" => self.one_of(value, &field, &["todotxt", "markdown"]),
+                "archive" => self.one_of(value, &field, &["remove", "keep"]),
+                "glob" => self.glob(value, &field),
+                // a `path` or `git` that isn't there yet is shown on the page
+                // by the store, rather than stopping every other file
+                _ => { self.string(value, &field); },
+            }
+        }
+        let is = |key: &str, value: &str| entries.iter().any(|&(k, v)| k == key && v.as_str() == Some(value));
+        if is("kind", "directory") && is("todos", "true") {
+            self.problem(node.line, field, "a directory can't have `todos`".into());
+        }
+    }
+
+    fn root(&mut self, node: &Marked) {
+        let mut has_local = false;
+        for (key, value) in self.mapping(node, "", TOP_LEVEL_KEYS) {
+            match key {
+                "local" => {
+                    has_local = true;
+                    match value.node {
+                        Node::Seq(ref files) => {
+                            for (i, file) in files.iter().enumerate() {
+                                self.local_file(file, &format!("local[{}]", i));
+                            }
+                        },
+                        _ => self.problem(value.line, "local", "expected a list of files".into()),
+                    }
+                },
+                "backups" | "history" => {
+                    for (field, value) in self.mapping(value, key, RETENTION_KEYS) {
+                        if !value.is_null() {
+                            self.number(value, &format!("{}.{}", key, field));
+                        }
+                    }
+                },
+                "deadlines" => { self.string(value, "deadlines"); },
+                _ => {},
+            }
+        }
+        if !has_local {
+            self.problem(node.line, "", "missing `local`, the list of files to show".into());
+        }
+    }
+}
+
+/// Everything wrong with the config file `contents`.
+pub fn check(contents: &str) -> Vec<Problem> {
+    let mut builder = TreeBuilder::default();
+    if let Err(e) = Parser::new(contents.chars()).load(&mut builder, false) {
+        // the message says where the problem is
+        return vec![Problem { line: 0, field: String::new(), message: e.to_string() }];
+    }
+
+    let mut checker = Checker { problems: vec![] };
+    match builder.root {
+        Some(ref root) if !root.is_null() => checker.root(root),
+        _ => checker.problem(0, "", "the config file is empty".into()),
+    }
+    checker.problems.sort_by_key(|p| p.line);
+    checker.problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_every_problem() {
+        let dir = ::tempfile::tempdir().unwrap();
+        let todo_path = dir.path().join("todo.txt");
+        ::std::fs::File::create(&todo_path).unwrap();
+
+        let config = format!("local:
+  - path: {}
+    todos: yes
+    frequency_goal_seconds: 3 fortnights
+  - name: missing
+    path: {}/nope.txt
+    colour: red
+backup:
+  keep_days: 3
+", todo_path.display(), dir.path().display());
+
+        let problems:Vec<String> = check(&config).iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert_eq!(problems[0], "line 3: local[0].todos: expected true or false, not `yes`");
+        assert!(problems[1].starts_with("line 4: local[0].frequency_goal_seconds: `3 fortnights` isn't a duration"));
+        assert!(problems[2].starts_with("line 7: local[1]: unknown key `colour`"));
+        assert!(problems[3].starts_with("line 8: unknown key `backup`"));
+
+        assert!(check("local: [\n").len() == 1);
+        assert_eq!(check("").len(), 1);
+        assert_eq!(check(&format!("local:\n  - path: {}\n    todos: true\n", todo_path.display())), vec![]);
+
+        let problems:Vec<String> = check(&format!("local:\n  - path: {}\n    kind: folder\n  - path: {}\n    kind: directory\n    todos: true\n",
+            todo_path.display(), dir.path().display())).iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems, vec!["line 3: local[0].kind: expected one of file, directory, not `folder`",
+                                  "line 4: local[1]: a directory can't have `todos`"]);
+
+        // globs needn't match anything yet
+        assert_eq!(check(&format!("local:\n  - path: {}/*/TODO.txt\n    git: \"{}/{{dir}}\"\n",
+            dir.path().display(), dir.path().display())), vec![]);
+        assert_eq!(check("local:\n  - path: ~/src/[/TODO.txt\n").len(), 1);
+
+        let problems:Vec<String> = check(&format!("local:\n  - path: {}\n    todos: true\n    format: md\n    archive: keep\n",
+            todo_path.display())).iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems, vec!["line 4: local[0].format: expected one of todotxt, markdown, not `md`"]);
+    }
+}
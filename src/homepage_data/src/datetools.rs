@@ -25,5 +25,185 @@ impl DateWhen {
         let duration = duration_relative_to_today(date);
         DateWhen::for_duration(duration)
     }
+
+    /// Like `for_date`, except that a time earlier today is already past.
+    pub fn for_datetime(datetime: &::chrono::NaiveDateTime) -> DateWhen {
+        if *datetime < now() {
+            DateWhen::Past
+        } else {
+            DateWhen::for_date(&datetime.date())
+        }
+    }
+}
+
+/// A due date or deadline, which may have a time of day. Times are local.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DateOrTime {
+    Date(::Date),
+    DateTime(::chrono::NaiveDateTime),
+}
+
+impl DateOrTime {
+    pub fn date(&self) -> ::Date {
+        match *self {
+            DateOrTime::Date(date) => date,
+            DateOrTime::DateTime(datetime) => datetime.date(),
+        }
+    }
+
+    pub fn date_when(&self) -> DateWhen {
+        match *self {
+            DateOrTime::Date(ref date) => DateWhen::for_date(date),
+            DateOrTime::DateTime(ref datetime) => DateWhen::for_datetime(datetime),
+        }
+    }
+
+    /// How long ago this is; negative if it's still to come. A date without a
+    /// time is measured in whole days, like `duration_relative_to_today`.
+    pub fn duration_relative_to_now(&self) -> ::time::Duration {
+        match *self {
+            DateOrTime::Date(ref date) => duration_relative_to_today(date),
+            DateOrTime::DateTime(ref datetime) => now().signed_duration_since(*datetime),
+        }
+    }
+}
+
+
+pub fn today() -> ::Date {
+    ::chrono::Local::today().naive_local()
+}
+
+pub fn now() -> ::chrono::NaiveDateTime {
+    ::chrono::Local::now().naive_local()
+}
+
+/// Adds calendar months to a date, clamping the day to the end of the
+/// resulting month (so Jan 31 + 1 month is Feb 28 or 29).
+pub fn add_months(date: &::Date, months: i32) -> ::Date {
+    use chrono::Datelike;
+
+    let total = date.year() * 12 + date.month0() as i32 + months;
+    let (year, month0) = if total >= 0 {
+        (total / 12, (total % 12) as u32)
+    } else {
+        ((total - 11) / 12, (((total % 12) + 12) % 12) as u32)
+    };
+
+    let mut day = date.day();
+    loop {
+        if let Some(d) = ::Date::from_ymd_opt(year, month0 + 1, day) {
+            return d;
+        }
+        day -= 1;
+    }
 }
 
+/// Adds weekdays to a date, skipping Saturdays and Sundays.
+pub fn add_business_days(date: &::Date, days: u32) -> ::Date {
+    use chrono::{Datelike, Weekday};
+
+    let mut d = *date;
+    let mut remaining = days;
+    while remaining > 0 {
+        d = d.succ();
+        match d.weekday() {
+            Weekday::Sat | Weekday::Sun => {},
+            _ => remaining -= 1,
+        }
+    }
+    d
+}
+
+/// Formats a duration to the minute in a form that fits in a tag and that
+/// humantime reads back, like `1h30m` or `45m`.
+pub fn format_duration(duration: &::std::time::Duration) -> String {
+    let minutes = (duration.as_secs() + 30) / 60;
+    match (minutes / 60, minutes % 60) {
+        (0, m) => format!("{}m", m),
+        (h, 0) => format!("{}h", h),
+        (h, m) => format!("{}h{}m", h, m),
+    }
+}
+
+/// Resolves a relative date, as typed into a `due:` or `t:` tag, against
+/// `today`. Understands `today`, `tomorrow`, `yesterday`, weekday names (the
+/// next one after today), `nextweek`, `nextmonth`, `nextyear`, and offsets
+/// like `3d`, `+2w` or `-1m` in the same units as `rec:`. ISO dates come back
+/// as they are.
+pub fn parse_relative_date(s: &str, today: &::Date) -> Option<::Date> {
+    use chrono::{Datelike, Weekday};
+
+    lazy_static! {
+        static ref OFFSET: ::regex::Regex =
+            ::regex::Regex::new(r"^([+-]?)(\d+)([dwmyb])$").unwrap();
+    }
+
+    if let Ok(date) = ::Date::parse_from_str(s, "%Y-%m-%d") {
+        return Some(date);
+    }
+
+    let s = s.to_lowercase();
+    let weekday = match s.as_str() {
+        "today" | "tod" => return Some(*today),
+        "tomorrow" | "tom" => return Some(today.succ()),
+        "yesterday" => return Some(today.pred()),
+        "nextweek" => return Some(*today + ::time::Duration::weeks(1)),
+        "nextmonth" => return Some(add_months(today, 1)),
+        "nextyear" => return Some(add_months(today, 12)),
+        "mon" | "monday" => Weekday::Mon,
+        "tue" | "tues" | "tuesday" => Weekday::Tue,
+        "wed" | "wednesday" => Weekday::Wed,
+        "thu" | "thur" | "thurs" | "thursday" => Weekday::Thu,
+        "fri" | "friday" => Weekday::Fri,
+        "sat" | "saturday" => Weekday::Sat,
+        "sun" | "sunday" => Weekday::Sun,
+        _ => {
+            let caps = OFFSET.captures(&s)?;
+            let count: i64 = caps[2].parse().ok()?;
+            let count = if &caps[1] == "-" { -count } else { count };
+            return match &caps[3] {
+                "d" => Some(*today + ::time::Duration::days(count)),
+                "w" => Some(*today + ::time::Duration::weeks(count)),
+                "m" => Some(add_months(today, count as i32)),
+                "y" => Some(add_months(today, count as i32 * 12)),
+                "b" if count >= 0 => Some(add_business_days(today, count as u32)),
+                _ => None,
+            };
+        },
+    };
+
+    let days_ahead = (weekday.num_days_from_monday() + 7 - today.weekday().num_days_from_monday()) % 7;
+    Some(*today + ::time::Duration::days(if days_ahead == 0 { 7 } else { days_ahead as i64 }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_dates() {
+        // a Wednesday
+        let today = ::Date::from_ymd(2018, 5, 2);
+        let parse = |s| parse_relative_date(s, &today);
+
+        assert_eq!(parse("2018-06-01"), Some(::Date::from_ymd(2018, 6, 1)));
+        assert_eq!(parse("tomorrow"), Some(::Date::from_ymd(2018, 5, 3)));
+        assert_eq!(parse("Fri"), Some(::Date::from_ymd(2018, 5, 4)));
+        assert_eq!(parse("wed"), Some(::Date::from_ymd(2018, 5, 9)));
+        assert_eq!(parse("+3d"), Some(::Date::from_ymd(2018, 5, 5)));
+        assert_eq!(parse("-1w"), Some(::Date::from_ymd(2018, 4, 25)));
+        assert_eq!(parse("2b"), Some(::Date::from_ymd(2018, 5, 4)));
+        assert_eq!(parse("nextmonth"), Some(::Date::from_ymd(2018, 6, 2)));
+        assert_eq!(parse("someday"), None);
+        assert_eq!(parse("2018-13-40"), None);
+    }
+
+    #[test]
+    fn durations() {
+        use std::time::Duration;
+        assert_eq!(format_duration(&Duration::from_secs(20)), "0m");
+        assert_eq!(format_duration(&Duration::from_secs(45 * 60)), "45m");
+        assert_eq!(format_duration(&Duration::from_secs(2 * 3600)), "2h");
+        assert_eq!(format_duration(&Duration::from_secs(90 * 60 + 40)), "1h31m");
+    }
+}
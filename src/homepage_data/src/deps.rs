@@ -0,0 +1,84 @@
+//! Dependencies between tasks.
+//!
+//! `dep:report` means a task can't be started until the task with `id:report`
+//! is finished; several ids can be given as `dep:report,slides`. A task is
+//! blocked while any of the tasks it depends on is unfinished. Ids that
+//! don't match any task (usually because it was finished and archived)
+//! don't block anything.
+
+use std::collections::HashSet;
+
+use todo::Task;
+use TaskWithContext;
+
+/// The ids in a task's `dep:` tag.
+pub fn dependencies(task: &Task) -> Vec<String> {
+    match task.tags.get("dep") {
+        Some(deps) => deps.split(',')
+            .filter(|d| !d.is_empty())
+            .map(|d| d.to_string())
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// The `id:` tags of all unfinished tasks, including subtasks.
+pub fn open_ids(todos: &[TaskWithContext]) -> HashSet<String> {
+    todos.iter()
+        .flat_map(|t| t.with_subtasks())
+        .filter(|t| !t.task.finished)
+        .filter_map(|t| t.task.tags.get("id").cloned())
+        .collect()
+}
+
+/// Fills in `blocked_by` for every unfinished task, given the ids of the
+/// unfinished tasks in all files.
+pub fn mark_blocked(todos: &mut [TaskWithContext], open: &HashSet<String>) {
+    for todo in todos.iter_mut() {
+        todo.blocked_by = if todo.task.finished {
+            vec![]
+        } else {
+            dependencies(&todo.task).into_iter()
+                .filter(|d| open.contains(d))
+                .collect()
+        };
+        mark_blocked(&mut todo.subtasks, open);
+    }
+}
+
+/// All tasks, including subtasks, that depend on the task with id `id`.
+pub fn dependents<'a>(todos: &'a [TaskWithContext], id: &str) -> Vec<&'a TaskWithContext> {
+    todos.iter()
+        .flat_map(|t| t.with_subtasks())
+        .filter(|t| dependencies(&t.task).iter().any(|d| d == id))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn todo(line: &str) -> TaskWithContext {
+        TaskWithContext::new("todo.txt", 0, line, Task::from_str(line).unwrap(), "")
+    }
+
+    #[test]
+    fn blocked_until_dependencies_are_finished() {
+        let mut todos = vec![
+            todo("write report id:report"),
+            todo("x book room id:room"),
+            todo("present dep:report,room,archived"),
+        ];
+
+        let open = open_ids(&todos);
+        mark_blocked(&mut todos, &open);
+        assert_eq!(todos[2].blocked_by, vec!["report".to_string()]);
+        assert_eq!(dependents(&todos, "room").len(), 1);
+
+        todos[0].task.finished = true;
+        let open = open_ids(&todos);
+        mark_blocked(&mut todos, &open);
+        assert!(todos[2].blocked_by.is_empty());
+    }
+}
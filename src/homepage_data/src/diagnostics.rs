@@ -0,0 +1,51 @@
+//! Problems found while reading the user's files, collected so they can be
+//! shown on the page instead of only going to stderr.
+
+use std::fmt;
+
+use todo::ParseError;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Diagnostic {
+    pub file: String,
+    /// One-based line number, or zero if it applies to the whole file.
+    pub line: usize,
+    /// One-based character column, or zero if it applies to the whole line.
+    pub column: usize,
+    pub reason: String,
+}
+
+impl Diagnostic {
+    /// A mistake in the task on line `index` (zero-based) of `file`, whose
+    /// text starts `offset` columns into the line.
+    pub fn parse_error(file: &str, index: usize, offset: usize, error: &ParseError) -> Diagnostic {
+        Diagnostic {
+            file: file.to_string(),
+            line: index + 1,
+            column: error.column + offset,
+            reason: format!("{}", error.kind),
+        }
+    }
+
+    /// A file or directory in the config that isn't there.
+    pub fn missing(file: &str) -> Diagnostic {
+        Diagnostic {
+            file: file.to_string(),
+            line: 0,
+            column: 0,
+            reason: "there's nothing here".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.file, self.reason)
+        } else if self.column > 0 {
+            write!(f, "{}:{}:{}: {}", self.file, self.line, self.column, self.reason)
+        } else {
+            write!(f, "{}:{}: {}", self.file, self.line, self.reason)
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Folders shown as lists.
+//!
+//! A `local:` entry with `kind: directory` lists the files in a folder, each
+//! with how long ago it was changed and a link to open it:
+//!
+//! ```yaml
+//! local:
+//!   - path: ~/Dropbox/To read
+//!     kind: directory
+//!     glob: "*.pdf"
+//!     done_dir: read
+//! ```
+//!
+//! Only files whose names match `glob` are listed, if it's set; hidden files
+//! and folders never are. Completing an item moves it into `done_dir`
+//! (`done` unless set), a folder inside the listed one that's created when
+//! it's first needed.
+//!
+//! The folder gets a history like a file does, so goals work the same way:
+//! its modification time is the newest of the folder's own and those of its
+//! items, and its size is the number of items.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use failure::ResultExt;
+
+use history::Metrics;
+use {FileState, LocalFileDesc};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DirectoryItem {
+    /// The file's name within the folder.
+    pub name: String,
+    pub path: String,
+    pub modified: SystemTime,
+}
+
+impl DirectoryItem {
+    pub fn age(&self) -> Duration {
+        SystemTime::now().duration_since(self.modified).unwrap_or_default()
+    }
+}
+
+#[derive(Debug)]
+pub struct ItemNotFound {
+    pub source: String,
+    pub name: String,
+}
+
+impl fmt::Display for ItemNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "no item {} in {}", self.name, self.source)
+    }
+}
+
+impl ::failure::Fail for ItemNotFound {}
+
+fn pattern(desc: &LocalFileDesc) -> Result<Option<::glob::Pattern>, ::failure::Error> {
+    if desc.glob.is_empty() {
+        return Ok(None);
+    }
+    let pattern = ::glob::Pattern::new(&desc.glob)
+        .map_err(|e| format_err!("`{}` isn't a glob pattern: {}", desc.glob, e))?;
+    Ok(Some(pattern))
+}
+
+/// The files listed for a directory source, oldest first.
+pub fn list(desc: &LocalFileDesc) -> Result<Vec<DirectoryItem>, ::failure::Error> {
+    let dir = desc.expanded_path();
+    let pattern = pattern(desc)?;
+    let mut items = vec![];
+    for entry in fs::read_dir(&dir).context(format!("missing directory {}", dir))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') || pattern.as_ref().map(|p| !p.matches(&name)).unwrap_or(false) {
+            continue;
+        }
+        // the metadata of what a symlink points to
+        let metadata = match fs::metadata(entry.path()) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        items.push(DirectoryItem {
+            name,
+            path: entry.path().to_string_lossy().to_string(),
+            modified: metadata.modified()?,
+        });
+    }
+    items.sort_by(|a, b| a.modified.cmp(&b.modified).then_with(|| a.name.cmp(&b.name)));
+    Ok(items)
+}
+
+fn done_dir(desc: &LocalFileDesc) -> PathBuf {
+    Path::new(&desc.expanded_path()).join(&desc.done_dir)
+}
+
+/// The state recorded in the folder's history.
+pub fn state(desc: &LocalFileDesc, items: &[DirectoryItem]) -> Result<FileState, ::failure::Error> {
+    let modified = fs::metadata(desc.expanded_path())?.modified()?;
+    Ok(FileState {
+        modification_time: items.iter().map(|i| i.modified).fold(modified, ::std::cmp::max),
+        size: items.len() as u64,
+    })
+}
+
+/// The folder's items count as open tasks, and what's in `done_dir` as
+/// finished ones.
+pub fn metrics(desc: &LocalFileDesc, items: &[DirectoryItem]) -> Result<Metrics, ::failure::Error> {
+    let finished_tasks = match fs::read_dir(done_dir(desc)) {
+        Ok(entries) => entries.filter_map(Result::ok)
+            .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+            .count(),
+        Err(_) => 0,
+    };
+    Ok(Metrics {
+        lines: items.len(),
+        open_tasks: items.len(),
+        finished_tasks,
+        ..Metrics::default()
+    })
+}
+
+/// Moves the item `name` into the folder's `done_dir`, and returns where it
+/// went.
+pub fn complete(desc: &LocalFileDesc, name: &str) -> Result<PathBuf, ::failure::Error> {
+    let item = match list(desc)?.into_iter().find(|i| i.name == name) {
+        Some(item) => item,
+        None => return Err(ItemNotFound { source: desc.readable_name().to_string(), name: name.to_string() }.into()),
+    };
+    let done_dir = done_dir(desc);
+    fs::create_dir_all(&done_dir).context(format!("could not create {}", done_dir.display()))?;
+    let destination = done_dir.join(&item.name);
+    if destination.exists() {
+        return Err(format_err!("there's already a {} in {}", item.name, done_dir.display()));
+    }
+    fs::rename(&item.path, &destination)
+        .context(format!("could not move {} to {}", item.path, done_dir.display()))?;
+    ::store::mark_stale();
+    Ok(destination)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn lists_and_completes_items() {
+        let dir = ::tempfile::tempdir().unwrap();
+        for name in &["paper.pdf", "notes.txt", ".hidden.pdf", "book.pdf"] {
+            File::create(dir.path().join(name)).unwrap().write_all(name.as_bytes()).unwrap();
+        }
+        fs::create_dir(dir.path().join("folder.pdf")).unwrap();
+        let desc: LocalFileDesc = ::serde_yaml::from_str(&format!(
+            "path: {}\nkind: directory\nglob: \"*.pdf\"\n", dir.path().display())).unwrap();
+
+        let items = list(&desc).unwrap();
+        let mut names:Vec<&str> = items.iter().map(|i| i.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["book.pdf", "paper.pdf"]);
+        assert_eq!(state(&desc, &items).unwrap().size, 2);
+
+        let moved = complete(&desc, "paper.pdf").unwrap();
+        assert_eq!(moved, dir.path().join("done").join("paper.pdf"));
+        assert!(moved.exists());
+        let items = list(&desc).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(metrics(&desc, &items).unwrap().finished_tasks, 1);
+
+        // only listed items can be completed
+        for name in &["paper.pdf", "notes.txt", "../book.pdf"] {
+            assert!(complete(&desc, name).unwrap_err().downcast::<ItemNotFound>().is_ok(), "{}", name);
+        }
+    }
+}
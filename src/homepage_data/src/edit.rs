@@ -0,0 +1,274 @@
+//! Editing todo files in place.
+//!
+//! Rewriting a task through its `Display` impl reorders its tags and loses
+//! its spacing, so instead we keep every line's original text and line
+//! ending (and the file's byte order mark), and only splice in the bytes a
+//! change actually needs.
+
+use todo::{spans, tag_spans};
+use datetools::parse_relative_date;
+
+const BOM: &str = "\u{feff}";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Line {
+    pub text: String,
+    /// `"\n"`, `"\r\n"`, or empty for a last line without a newline.
+    pub ending: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TodoFile {
+    pub bom: bool,
+    pub lines: Vec<Line>,
+}
+
+impl TodoFile {
+    pub fn parse(contents: &str) -> TodoFile {
+        let bom = contents.starts_with(BOM);
+        let mut rest = if bom { &contents[BOM.len()..] } else { contents };
+
+        let mut lines = vec![];
+        while !rest.is_empty() {
+            let (line, ending, next) = match rest.find('\n') {
+                Some(i) if rest[..i].ends_with('\r') => (&rest[..i - 1], "\r\n", &rest[i + 1..]),
+                Some(i) => (&rest[..i], "\n", &rest[i + 1..]),
+                None => (rest, "", ""),
+            };
+            lines.push(Line { text: line.to_string(), ending: ending.to_string() });
+            rest = next;
+        }
+
+        TodoFile { bom, lines }
+    }
+
+    pub fn texts(&self) -> Vec<&str> {
+        self.lines.iter().map(|l| l.text.as_str()).collect()
+    }
+
+    /// The line ending used by the file, for adding new lines.
+    pub fn line_ending(&self) -> &str {
+        self.lines.iter()
+            .map(|l| l.ending.as_str())
+            .find(|e| !e.is_empty())
+            .unwrap_or("\n")
+    }
+
+    pub fn set_text(&mut self, index: usize, text: String) {
+        self.lines[index].text = text;
+    }
+
+    /// Adds a line at the end of the file, giving the current last line a
+    /// line ending first if it's missing one.
+    pub fn push_line(&mut self, text: String) {
+        let ending = self.line_ending().to_string();
+        let had_final_newline = match self.lines.last_mut() {
+            Some(last) if last.ending.is_empty() => {
+                last.ending = ending.clone();
+                false
+            },
+            Some(_) => true,
+            None => false,
+        };
+
+        self.lines.push(Line {
+            text,
+            ending: if had_final_newline { ending } else { String::new() },
+        });
+    }
+
+    /// Adds a line before `index`, using the file's line ending.
+    pub fn insert_line(&mut self, index: usize, text: String) {
+        if index >= self.lines.len() {
+            return self.push_line(text);
+        }
+        let ending = self.line_ending().to_string();
+        self.lines.insert(index, Line { text, ending });
+    }
+
+    pub fn remove_line(&mut self, index: usize) -> Line {
+        let removed = self.lines.remove(index);
+        if index == self.lines.len() && index > 0 {
+            // keep the file's trailing newline (or lack of one) the same
+            self.lines[index - 1].ending = removed.ending.clone();
+        }
+        removed
+    }
+}
+
+impl ::std::fmt::Display for TodoFile
+{
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result
+    {
+        if self.bom {
+            f.write_str(BOM)?;
+        }
+        for line in &self.lines {
+            f.write_str(&line.text)?;
+            f.write_str(&line.ending)?;
+        }
+        Ok(())
+    }
+}
+
+/// Marks a task finished or unfinished the way the todo.txt format
+/// describes: a finished task starts with `x ` and the date it was finished,
+/// ahead of its creation date, and its priority moves into a `pri:` tag until
+/// it's unfinished again.
+pub fn set_finished(line: &str, finished: bool, today: &::Date) -> String {
+    let spans = spans(line);
+    match (spans.finished, finished) {
+        (None, true) => {
+            let (priority, rest) = match spans.priority {
+                Some(range) => (line[range.start + 1..range.start + 2].to_string(), &line[range.end..]),
+                None => (String::new(), &line[spans.indent..]),
+            };
+
+            let text = format!("{}x {} {}", &line[..spans.indent], today.format("%Y-%m-%d"), rest);
+            if priority.is_empty() {
+                text
+            } else {
+                set_tag(&text, "pri", &priority)
+            }
+        },
+        (Some(finished), false) => {
+            let mut text = line.to_string();
+
+            // the first date is the completion date, whether or not there's
+            // a creation date after it
+            if let Some(range) = spans.first_date {
+                text.replace_range(range, "");
+            }
+            text.replace_range(finished, "");
+
+            // only a priority the prefix can hold goes back there; anything
+            // else stays a tag
+            let is_priority = |value: &str| value.len() == 1 && value.as_bytes()[0] >= b'A' && value.as_bytes()[0] <= b'Z';
+            match tag_spans(&text).into_iter().find(|t| t.key == "pri") {
+                Some(ref tag) if spans.priority.is_none() && is_priority(&text[tag.value.clone()]) => {
+                    let priority = text[tag.value.clone()].to_string();
+                    text.replace_range(tag.whole.clone(), "");
+                    text.insert_str(spans.indent, &format!("({}) ", priority));
+                    text
+                },
+                _ => text,
+            }
+        },
+        _ => line.to_string(),
+    }
+}
+
+/// Changes the value of the tag `key`, or adds it to the end of the line.
+pub fn set_tag(line: &str, key: &str, value: &str) -> String {
+    let mut text = line.to_string();
+    match tag_spans(line).into_iter().find(|t| t.key == key) {
+        Some(tag) => text.replace_range(tag.value, value),
+        None => {
+            if !text.is_empty() && !text.ends_with(' ') {
+                text.push(' ');
+            }
+            text.push_str(&format!("{}:{}", key, value));
+        },
+    }
+    text
+}
+
+pub fn remove_tag(line: &str, key: &str) -> String {
+    let mut text = line.to_string();
+    if let Some(tag) = tag_spans(line).into_iter().find(|t| t.key == key) {
+        text.replace_range(tag.whole, "");
+    }
+    text
+}
+
+/// Replaces relative `due:` and `t:` dates (`due:fri`, `t:+3d`) with the ISO
+/// dates they mean as of `today`, so that they don't drift as time passes.
+/// Values that aren't dates at all are left for the parser to complain about.
+pub fn resolve_relative_dates(line: &str, today: &::Date) -> String {
+    let mut text = line.to_string();
+    for tag in tag_spans(line).into_iter().rev() {
+        if tag.key != "due" && tag.key != "t" {
+            continue;
+        }
+        if let Some(date) = parse_relative_date(&line[tag.value.clone()], today) {
+            text.replace_range(tag.value, &date.format("%Y-%m-%d").to_string());
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_preserves_bytes() {
+        let inputs = [
+            "",
+            "one",
+            "one\n",
+            "one\r\ntwo\r\n",
+            "\u{feff}(A) one\r\n\r\ntwo",
+            "mixed\r\nendings\nhere",
+        ];
+
+        for input in inputs.iter() {
+            assert_eq!(&TodoFile::parse(input).to_string(), input);
+        }
+    }
+
+    #[test]
+    fn push_and_remove_lines() {
+        let mut file = TodoFile::parse("\u{feff}one\r\ntwo");
+        file.push_line("three".into());
+        assert_eq!(file.to_string(), "\u{feff}one\r\ntwo\r\nthree");
+
+        file.remove_line(2);
+        assert_eq!(file.to_string(), "\u{feff}one\r\ntwo");
+
+        let mut file = TodoFile::parse("one\ntwo\n");
+        file.push_line("three".into());
+        assert_eq!(file.to_string(), "one\ntwo\nthree\n");
+    }
+
+    #[test]
+    fn edits_only_touch_what_they_need_to() {
+        let line = "(B) 2018-05-01  call   bob due:2018-05-03 @phone";
+
+        assert_eq!(set_tag(line, "due", "2018-06-01"), "(B) 2018-05-01  call   bob due:2018-06-01 @phone");
+        assert_eq!(set_tag(line, "est", "1h"), "(B) 2018-05-01  call   bob due:2018-05-03 @phone est:1h");
+        assert_eq!(remove_tag(line, "due"), "(B) 2018-05-01  call   bob @phone");
+    }
+
+    #[test]
+    fn finishing_follows_the_spec() {
+        let today = ::Date::from_ymd(2018, 5, 2);
+        let line = "(B) 2018-05-01  call   bob due:2018-05-03 @phone";
+
+        let done = set_finished(line, true, &today);
+        assert_eq!(done, "x 2018-05-02 2018-05-01  call   bob due:2018-05-03 @phone pri:B");
+        assert_eq!(set_finished(&done, true, &today), done);
+        assert_eq!(set_finished(&done, false, &today), line);
+        assert_eq!(set_finished(line, false, &today), line);
+
+        assert_eq!(set_finished("plain", true, &today), "x 2018-05-02 plain");
+        assert_eq!(set_finished("x 2018-05-02 plain", false, &today), "plain");
+        assert_eq!(set_finished("x plain", false, &today), "plain");
+
+        let nested = "    (A) subtask";
+        let done = set_finished(nested, true, &today);
+        assert_eq!(done, "    x 2018-05-02 subtask pri:A");
+        assert_eq!(set_finished(&done, false, &today), nested);
+
+        assert_eq!(set_finished("x 2018-05-02 report pri:high", false, &today), "report pri:high");
+        assert_eq!(set_finished("x 2018-05-02 report pri:b", false, &today), "report pri:b");
+    }
+
+    #[test]
+    fn relative_dates_become_iso() {
+        let today = ::Date::from_ymd(2018, 5, 2);
+        assert_eq!(resolve_relative_dates("  call bob due:fri  t:Tomorrow @phone", &today),
+                   "  call bob due:2018-05-04  t:2018-05-03 @phone");
+        assert_eq!(resolve_relative_dates("call bob due:someday", &today), "call bob due:someday");
+    }
+}
@@ -1,6 +1,7 @@
 #![allow(non_snake_case)]
 
 use chrono;
+use datetools::DateOrTime;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DateEntry {
@@ -10,18 +11,26 @@ pub struct DateEntry {
 }
 
 impl DateEntry {
-    pub fn to_naive_date(&self) -> chrono::format::ParseResult<chrono::NaiveDate> {
+    /// When the event starts, in local time. All-day events have just a date.
+    ///
+    /// `dateTime` values from the calendar API carry a UTC offset, so an event
+    /// at 23:59 PST shows up at the right moment wherever the page is viewed.
+    /// Without an offset, the time is taken to be local.
+    pub fn to_date_or_time(&self) -> chrono::format::ParseResult<DateOrTime> {
         if self.dateTime.is_empty() {
-            assert!(self.dateTime.is_empty());
-            chrono::NaiveDate::parse_from_str(&self.date, "%Y-%m-%d")
+            chrono::NaiveDate::parse_from_str(&self.date, "%Y-%m-%d").map(DateOrTime::Date)
         } else {
-            assert!(!self.dateTime.is_empty(), "invalid: {:?}", self);
-            // assert!(self.timeZone.is_empty());
-            Ok(
-                chrono::NaiveDateTime::parse_from_str(&self.dateTime, "%+").unwrap().date()
-            )
+            match chrono::DateTime::parse_from_rfc3339(&self.dateTime) {
+                Ok(datetime) => Ok(DateOrTime::DateTime(datetime.with_timezone(&chrono::Local).naive_local())),
+                Err(_) => chrono::NaiveDateTime::parse_from_str(&self.dateTime, "%Y-%m-%dT%H:%M:%S")
+                    .map(DateOrTime::DateTime),
+            }
         }
     }
+
+    pub fn to_naive_date(&self) -> chrono::format::ParseResult<chrono::NaiveDate> {
+        self.to_date_or_time().map(|d| d.date())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -56,6 +65,24 @@ mod tests {
         };
     }
 
+    #[test]
+    fn parse_gcal_times() {
+        use super::*;
+        use chrono::TimeZone;
+
+        let entry = DateEntry {
+            date: String::new(),
+            dateTime: "2018-05-03T23:59:00-08:00".to_string(),
+            timeZone: "America/Los_Angeles".to_string(),
+        };
+        let expected = chrono::FixedOffset::west(8 * 3600).ymd(2018, 5, 3).and_hms(23, 59, 0)
+            .with_timezone(&chrono::Local).naive_local();
+        assert_eq!(entry.to_date_or_time(), Ok(DateOrTime::DateTime(expected)));
+
+        let entry = DateEntry { date: "2018-05-04".to_string(), dateTime: String::new(), timeZone: String::new() };
+        assert_eq!(entry.to_date_or_time(), Ok(DateOrTime::Date(chrono::NaiveDate::from_ymd(2018, 5, 4))));
+    }
+
         /*
     use ::chrono;
 
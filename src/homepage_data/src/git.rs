@@ -0,0 +1,230 @@
+//! What's going on in a file's `git:` repo, read with libgit2 rather than by
+//! running `git`, so neither a repo without commits nor a machine without
+//! git can break the page.
+//!
+//! For a file with a `git:` repo, `frequency_goal_seconds` and streaks are
+//! measured against commits to the repo instead of changes to the file.
+
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use git2::{Branch, ErrorCode, Repository, Sort, StatusOptions};
+
+use atomic::file_state;
+use FileState;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// How far back commits are looked at.
+const HISTORY_DAYS: u64 = 365;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RepoStatus {
+    /// When the commits on the current branch were made, oldest first, going
+    /// back a year. The newest is always there, however old it is.
+    pub commit_times: Vec<SystemTime>,
+    /// Whether any tracked files have changes that aren't committed.
+    pub dirty: bool,
+    /// How many commits the upstream branch doesn't have yet, if there is one.
+    pub unpushed: Option<usize>,
+}
+
+impl RepoStatus {
+    /// `None` if nothing has been committed yet.
+    pub fn last_commit(&self) -> Option<SystemTime> {
+        self.commit_times.last().cloned()
+    }
+
+    pub fn commits_in_last_days(&self, days: u64) -> usize {
+        let now = SystemTime::now();
+        self.commit_times.iter()
+            .filter(|&&t| now.duration_since(t).unwrap_or_default() <= Duration::from_secs(days * DAY))
+            .count()
+    }
+
+    pub fn commits_7_days(&self) -> usize {
+        self.commits_in_last_days(7)
+    }
+
+    pub fn commits_30_days(&self) -> usize {
+        self.commits_in_last_days(30)
+    }
+
+    pub fn unpushed_count(&self) -> usize {
+        self.unpushed.unwrap_or(0)
+    }
+
+    /// Everything above, in a sentence.
+    pub fn summary(&self) -> String {
+        let mut parts = vec![format!("{} commits in the last 7 days, {} in the last 30",
+            self.commits_7_days(), self.commits_30_days())];
+        if self.dirty {
+            parts.push("uncommitted changes".to_string());
+        }
+        match self.unpushed {
+            Some(0) => {},
+            Some(n) => parts.push(format!("{} unpushed commits", n)),
+            None => parts.push("no upstream branch".to_string()),
+        }
+        parts.join("; ")
+    }
+}
+
+/// The files git changes when something is committed, staged, pushed or
+/// fetched. Changes that haven't been staged don't touch any of them.
+///
+/// `dir` can be anywhere in the repo's work tree, which can also be a
+/// worktree or a submodule, whose git directory isn't a `.git` in it.
+pub fn watched_paths(dir: &str) -> Vec<PathBuf> {
+    let repo = match Repository::discover(dir) {
+        Ok(repo) => repo,
+        Err(_) => return vec![],
+    };
+    // a worktree has its own HEAD and index, and shares everything else
+    let git_dir = repo.path();
+    let common_dir = repo.commondir();
+    let mut paths = vec![git_dir.join("logs").join("HEAD"), git_dir.join("index"),
+                         common_dir.join("FETCH_HEAD"), common_dir.join("packed-refs")];
+
+    // pushing only moves the remote-tracking branches
+    if let Ok(refs) = repo.references_glob("refs/remotes/*") {
+        for reference in refs.filter_map(Result::ok) {
+            if let Some(name) = reference.name() {
+                paths.push(common_dir.join(name));
+                paths.push(common_dir.join("logs").join(name));
+            }
+        }
+    }
+    paths
+}
+
+/// One state for all of `watched_paths`, which changes whenever any of
+/// theirs does, or `None` if there's no repo.
+pub fn repo_state(dir: &str) -> Result<Option<FileState>, ::failure::Error> {
+    if Repository::discover(dir).is_err() {
+        return Ok(None);
+    }
+    let mut combined = FileState { modification_time: UNIX_EPOCH, size: 0 };
+    for path in watched_paths(dir) {
+        if let Some(state) = file_state(&path.to_string_lossy())? {
+            combined.modification_time = combined.modification_time.max(state.modification_time);
+            combined.size += state.size;
+        }
+    }
+    Ok(Some(combined))
+}
+
+fn commit_time(commit: &::git2::Commit) -> SystemTime {
+    // when the commit landed, rather than when its change was first written
+    let secs = commit.committer().when().seconds();
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        UNIX_EPOCH
+    }
+}
+
+/// Reads the state of the repo that `dir` is in.
+pub fn status(dir: &str) -> Result<RepoStatus, ::failure::Error> {
+    let repo = Repository::discover(dir)?;
+
+    let head = match repo.head() {
+        Ok(head) => Some(head),
+        Err(ref e) if e.code() == ErrorCode::UnbornBranch || e.code() == ErrorCode::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut commit_times = vec![];
+    let mut unpushed = None;
+    if let Some(head) = head {
+        let cutoff = SystemTime::now() - Duration::from_secs(HISTORY_DAYS * DAY);
+        let mut walk = repo.revwalk()?;
+        walk.set_sorting(Sort::TIME)?;
+        walk.push_head()?;
+        for oid in walk {
+            let time = commit_time(&repo.find_commit(oid?)?);
+            if time < cutoff && !commit_times.is_empty() {
+                break;
+            }
+            commit_times.push(time);
+        }
+        commit_times.sort();
+
+        if head.is_branch() {
+            let local = head.target();
+            if let (Ok(upstream), Some(local)) = (Branch::wrap(head).upstream(), local) {
+                if let Some(remote) = upstream.get().target() {
+                    unpushed = Some(repo.graph_ahead_behind(local, remote)?.0);
+                }
+            }
+        }
+    }
+
+    let mut options = StatusOptions::new();
+    options.include_untracked(false).include_ignored(false);
+    let dirty = !repo.statuses(Some(&mut options))?.is_empty();
+
+    Ok(RepoStatus { commit_times, dirty, unpushed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{Signature, Time};
+    use std::path::Path;
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn reads_commits_and_changes() {
+        let dir = ::tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let repo = Repository::init(path).unwrap();
+
+        // nothing committed yet
+        let status = super::status(path).unwrap();
+        assert_eq!(status.last_commit(), None);
+        assert_eq!(status.unpushed, None);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        let mut parent = None;
+        for &days_ago in &[40, 20, 3, 1] {
+            File::create(dir.path().join("todo.txt")).unwrap().write_all(format!("{}\n", days_ago).as_bytes()).unwrap();
+            let mut index = repo.index().unwrap();
+            index.add_path(Path::new("todo.txt")).unwrap();
+            index.write().unwrap();
+            let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+            // written long before, but committed `days_ago`
+            let author = Signature::new("someone", "someone@example.com", &Time::new(now - 300 * DAY as i64, 0)).unwrap();
+            let committer = Signature::new("someone", "someone@example.com", &Time::new(now - days_ago * DAY as i64, 0)).unwrap();
+            let parents:Vec<::git2::Commit> = parent.iter().map(|&id| repo.find_commit(id).unwrap()).collect();
+            let parents:Vec<&::git2::Commit> = parents.iter().collect();
+            parent = Some(repo.commit(Some("HEAD"), &author, &committer, "change", &tree, &parents).unwrap());
+        }
+
+        let status = super::status(path).unwrap();
+        assert_eq!(status.commit_times.len(), 4);
+        assert_eq!(status.commits_7_days(), 2);
+        assert_eq!(status.commits_30_days(), 3);
+        assert!(!status.dirty);
+        assert_eq!(status.unpushed, None);
+
+        File::create(dir.path().join("todo.txt")).unwrap().write_all(b"edited\n").unwrap();
+        assert!(super::status(path).unwrap().dirty);
+        assert!(repo_state(path).unwrap().is_some());
+        assert_eq!(repo_state(dir.path().join("nope").to_str().unwrap()).unwrap(), None);
+
+        // a directory inside the work tree finds the repo too
+        let sub = dir.path().join("notes");
+        ::std::fs::create_dir(&sub).unwrap();
+        let sub = sub.to_str().unwrap();
+        assert_eq!(super::status(sub).unwrap().commit_times.len(), 4);
+        assert!(repo_state(sub).unwrap().is_some());
+
+        // and a push shows up as a change to the remote-tracking branch
+        repo.reference("refs/remotes/origin/master", parent.unwrap(), true, "update by push").unwrap();
+        let watched = watched_paths(sub);
+        assert!(watched.contains(&repo.path().join("refs/remotes/origin/master")));
+        assert!(watched.contains(&repo.path().join("logs/refs/remotes/origin/master")));
+    }
+}
@@ -0,0 +1,234 @@
+//! Goals for how often, and how, a file should change.
+//!
+//! `frequency_goal_seconds` asks for a file to be changed at least that
+//! often. Since that's met by touching the file, a file can also be asked to
+//! grow by some number of words a day, or to keep its open tasks down:
+//!
+//! ```yaml
+//! local:
+//!   - path: ~/Dropbox/Journal.txt
+//!     words_per_day: 200
+//!   - path: ~/Dropbox/todo.txt
+//!     todos: true
+//!     open_tasks_max: 20
+//! ```
+//!
+//! Growth is measured against the newest snapshot in the file's history
+//! that's at least a day old, so there's nothing to go on until the history
+//! goes back that far.
+//!
+//! Files with a `frequency_goal_seconds` also get streaks. A streak is a run
+//! of changes with no gap between them longer than the goal, and its length
+//! is the number of goal periods it spans: changing a file every evening for
+//! five days is a streak of five days with a `1day` goal, and one of six
+//! days (three periods) with a `2days` goal. Streaks only go back as far as
+//! the file's history is kept.
+//!
+//! For a file with a `git:` repo, both are measured against commits to the
+//! repo instead.
+
+use std::time::{Duration, SystemTime};
+
+use git::RepoStatus;
+use history::Snapshot;
+use {LocalFileDesc, UpdateState};
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// How far back `Streaks::rate_30_days` looks.
+const RATE_DAYS: u64 = 30;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Streaks {
+    /// How long the file has kept up with its goal; zero if it's behind.
+    pub current: Duration,
+    pub longest: Duration,
+    /// The share of goal periods in the last 30 days that the file was
+    /// changed in, from 0 to 1.
+    pub rate_30_days: f64,
+}
+
+impl Streaks {
+    pub fn rate_percent(&self) -> u32 {
+        (self.rate_30_days * 100.0).round() as u32
+    }
+}
+
+/// Whether the goals set for a file are met, with a description of each
+/// that isn't.
+pub fn check(desc: &LocalFileDesc, history: &[Snapshot], repo: Option<&RepoStatus>, now: SystemTime) -> (UpdateState, Vec<String>) {
+    if desc.frequency_goal_seconds <= 0 && desc.words_per_day.is_none() && desc.open_tasks_max.is_none() {
+        return (UpdateState::NoGoal, vec![]);
+    }
+    let latest = match history.last() {
+        Some(latest) => latest,
+        None => return (UpdateState::NoGoal, vec![]),
+    };
+
+    let mut unmet = vec![];
+    if desc.frequency_goal_seconds > 0 {
+        let (last_change, what) = match repo {
+            Some(repo) => (repo.last_commit(), "committed to"),
+            None => (Some(latest.state.modification_time), "changed"),
+        };
+        let behind = match last_change {
+            Some(time) => now.duration_since(time).unwrap_or_default().as_secs() as i64 > desc.frequency_goal_seconds,
+            None => true,
+        };
+        if behind {
+            let goal = Duration::from_secs(desc.frequency_goal_seconds as u64);
+            unmet.push(format!("not {} for over {}", what, ::humantime::format_duration(goal)));
+        }
+    }
+
+    if let (Some(goal), Some(metrics)) = (desc.words_per_day, latest.metrics.as_ref()) {
+        let day_ago = now - Duration::from_secs(DAY);
+        let base = history.iter().rev()
+            .find(|s| s.state.modification_time <= day_ago)
+            .and_then(|s| s.metrics.as_ref());
+        if let Some(base) = base {
+            let growth = metrics.words as i64 - base.words as i64;
+            if growth < goal as i64 {
+                unmet.push(format!("{} of {} words in the last day", growth.max(0), goal));
+            }
+        }
+    }
+
+    if let (Some(max), Some(metrics)) = (desc.open_tasks_max, latest.metrics.as_ref()) {
+        if metrics.open_tasks > max {
+            unmet.push(format!("{} open tasks, more than {}", metrics.open_tasks, max));
+        }
+    }
+
+    let state = if unmet.is_empty() { UpdateState::Ok } else { UpdateState::NeedsUpdate };
+    (state, unmet)
+}
+
+/// The length of a run of changes from `first` to `last`, in whole goal
+/// periods.
+fn streak_length(first: SystemTime, last: SystemTime, goal: Duration) -> Duration {
+    let span = last.duration_since(first).unwrap_or_default().as_secs();
+    goal * (span / goal.as_secs() + 1) as u32
+}
+
+/// The file's streaks, if it has a frequency goal.
+pub fn streaks(desc: &LocalFileDesc, history: &[Snapshot], repo: Option<&RepoStatus>, now: SystemTime) -> Option<Streaks> {
+    if desc.frequency_goal_seconds <= 0 {
+        return None;
+    }
+    let goal = Duration::from_secs(desc.frequency_goal_seconds as u64);
+    let times:Vec<SystemTime> = match repo {
+        Some(repo) => repo.commit_times.clone(),
+        None => history.iter().map(|s| s.state.modification_time).collect(),
+    };
+    let (&first, &last) = (times.first()?, times.last()?);
+
+    let mut longest = Duration::from_secs(0);
+    let mut run_start = first;
+    for pair in times.windows(2) {
+        if pair[1].duration_since(pair[0]).unwrap_or_default() > goal {
+            longest = longest.max(streak_length(run_start, pair[0], goal));
+            run_start = pair[1];
+        }
+    }
+    let last_run = streak_length(run_start, last, goal);
+    longest = longest.max(last_run);
+    let current = if now.duration_since(last).unwrap_or_default() > goal {
+        Duration::from_secs(0)
+    } else {
+        last_run
+    };
+
+    // goal periods counting back from now, leaving out any from before the
+    // history starts
+    let periods = ((RATE_DAYS * DAY + goal.as_secs() - 1) / goal.as_secs()).max(1);
+    let (mut counted, mut met) = (0, 0);
+    for i in 0..periods {
+        let end = now - goal * i as u32;
+        let start = end - goal;
+        if end <= first {
+            break;
+        }
+        counted += 1;
+        if times.iter().any(|&t| t > start && t <= end) {
+            met += 1;
+        }
+    }
+    let rate_30_days = if counted == 0 { 0.0 } else { f64::from(met) / f64::from(counted) };
+
+    Some(Streaks { current, longest, rate_30_days })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use history::Metrics;
+    use FileState;
+
+    fn snapshot(hours_ago: u64, words: usize, open_tasks: usize, now: SystemTime) -> Snapshot {
+        Snapshot {
+            state: FileState { modification_time: now - Duration::from_secs(hours_ago * 60 * 60), size: 0 },
+            metrics: Some(Metrics { words, open_tasks, ..Metrics::default() }),
+        }
+    }
+
+    #[test]
+    fn growth_goals() {
+        let now = SystemTime::now();
+        let mut desc: LocalFileDesc = ::serde_yaml::from_str("path: journal.txt\nwords_per_day: 200\n").unwrap();
+        let history = vec![snapshot(50, 100, 0, now), snapshot(30, 300, 0, now), snapshot(2, 450, 0, now)];
+
+        let (state, unmet) = check(&desc, &history, None, now);
+        assert!(match state { UpdateState::NeedsUpdate => true, _ => false });
+        assert_eq!(unmet, vec!["150 of 200 words in the last day"]);
+
+        // not a day's worth of history yet
+        assert!(check(&desc, &history[2..], None, now).1.is_empty());
+
+        desc.words_per_day = None;
+        assert!(match check(&desc, &history, None, now).0 { UpdateState::NoGoal => true, _ => false });
+
+        desc.open_tasks_max = Some(20);
+        desc.frequency_goal_seconds = 60 * 60;
+        let history = vec![snapshot(3, 0, 25, now)];
+        assert_eq!(check(&desc, &history, None, now).1, vec!["not changed for over 1h", "25 open tasks, more than 20"]);
+
+        // with a repo, it's commits that count
+        let mut repo = RepoStatus { commit_times: vec![], dirty: false, unpushed: None };
+        desc.open_tasks_max = None;
+        let history = vec![snapshot(0, 0, 0, now)];
+        assert_eq!(check(&desc, &history, Some(&repo), now).1, vec!["not committed to for over 1h"]);
+        repo.commit_times.push(now - Duration::from_secs(60));
+        assert!(check(&desc, &history, Some(&repo), now).1.is_empty());
+    }
+
+    #[test]
+    fn streaks_over_goal_periods() {
+        let now = SystemTime::now();
+        let days = |n: u64| Duration::from_secs(n * DAY);
+        let mut desc: LocalFileDesc = ::serde_yaml::from_str("path: journal.txt\nfrequency_goal_seconds: 1day\n").unwrap();
+        // three days in a row, a gap, then the last five evenings
+        let hours_ago = [20 * 24 + 3, 19 * 24 + 3, 18 * 24 + 3, 4 * 24 + 3, 3 * 24 + 3, 2 * 24 + 3, 24 + 3, 3];
+        let history:Vec<Snapshot> = hours_ago.iter().map(|&h| snapshot(h, 0, 0, now)).collect();
+
+        let streaks = streaks(&desc, &history, None, now).unwrap();
+        assert_eq!(streaks.current, days(5));
+        assert_eq!(streaks.longest, days(5));
+        // 8 of the 21 days since the history starts
+        assert_eq!(streaks.rate_percent(), 38);
+
+        desc.frequency_goal_seconds = 2 * DAY as i64;
+        let streaks = super::streaks(&desc, &history, None, now).unwrap();
+        assert_eq!(streaks.current, days(6));
+        // 5 of the 11 two-day periods
+        assert_eq!(streaks.rate_percent(), 45);
+
+        // falling behind ends the current streak, but not the longest
+        let streaks = super::streaks(&desc, &history, None, now + days(3)).unwrap();
+        assert_eq!(streaks.current, days(0));
+        assert_eq!(streaks.longest, days(6));
+
+        desc.frequency_goal_seconds = 0;
+        assert_eq!(super::streaks(&desc, &history, None, now), None);
+    }
+}
@@ -0,0 +1,333 @@
+//! The history of each file's changes.
+//!
+//! Every time the page notices that a file has changed, a snapshot of it is
+//! added to a log in the data directory (`$XDG_DATA_HOME/encasa`, normally
+//! `~/.local/share/encasa`). Each line of the log is one snapshot:
+//!
+//! ```text
+//! 1533412800.000000000 1024 180 32 12 4 57 /home/kevin/Dropbox/todo.txt
+//! ```
+//!
+//! that is, the modification time, the size, the number of words, lines, open
+//! and finished tasks, the bytes added since the snapshot before, and the
+//! canonical path of the file. Snapshots from before there were metrics have
+//! only the time, size and path. Lines are only ever appended, except that
+//! once enough of them fall outside the `history:` retention limits the log
+//! is rewritten without them:
+//!
+//! ```yaml
+//! history:
+//!   keep_per_file: 200
+//!   keep_days: 365
+//! ```
+//!
+//! History used to be kept in a `.meta.yaml` file next to each file. Those
+//! are read the first time a file is seen, and can be deleted afterwards.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use failure::ResultExt;
+
+use atomic::{self, LockedFile};
+use edit::TodoFile;
+use todo::Task;
+use {markdown, FileFormat, FileState};
+
+static LOG_NAME: &str = "history.log";
+
+fn default_keep_per_file() -> Option<usize> {
+    Some(200)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Retention {
+    /// How many states to keep for each file.
+    #[serde(default = "default_keep_per_file")] pub keep_per_file: Option<usize>,
+    /// How many days to keep states for.
+    #[serde(default)] pub keep_days: Option<u64>,
+}
+
+impl Default for Retention {
+    fn default() -> Retention {
+        Retention { keep_per_file: default_keep_per_file(), keep_days: None }
+    }
+}
+
+impl Retention {
+    /// Drops the snapshots of one file, oldest first, that are outside the
+    /// limits. The newest is always kept, since it says how long it's been
+    /// since the file changed.
+    fn apply(&self, states: &mut Vec<Snapshot>) {
+        if let Some(days) = self.keep_days {
+            let max_age = Duration::from_secs(days * 24 * 60 * 60);
+            let newest = states.pop();
+            states.retain(|s| s.state.modification_time.elapsed().map(|age| age <= max_age).unwrap_or(true));
+            states.extend(newest);
+        }
+        if let Some(keep) = self.keep_per_file {
+            if states.len() > keep {
+                let extra = states.len() - keep;
+                states.drain(..extra);
+            }
+        }
+    }
+}
+
+/// What was in a file when a snapshot was taken.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    pub words: usize,
+    pub lines: usize,
+    /// Only counted in todo files.
+    pub open_tasks: usize,
+    pub finished_tasks: usize,
+    /// How much bigger the file is than at the snapshot before; negative if
+    /// it shrank, and 0 for the first one.
+    pub bytes_added: i64,
+}
+
+impl Metrics {
+    /// Tasks are counted if the file has them, in `tasks` format.
+    pub fn of(contents: &str, tasks: Option<FileFormat>) -> Metrics {
+        let mut metrics = Metrics {
+            words: contents.split_whitespace().count(),
+            lines: contents.lines().count(),
+            ..Metrics::default()
+        };
+        if tasks.is_some() {
+            for line in TodoFile::parse(contents).texts() {
+                let task = match tasks {
+                    Some(FileFormat::Markdown) => markdown::parse_line(line, None),
+                    _ => Some(line.parse::<Task>()),
+                };
+                match task {
+                    Some(Ok(ref task)) if task.subject.is_empty() => {},
+                    Some(Ok(ref task)) if task.finished => metrics.finished_tasks += 1,
+                    Some(Ok(_)) => metrics.open_tasks += 1,
+                    _ => {},
+                }
+            }
+        }
+        metrics
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub state: FileState,
+    /// `None` for snapshots taken before metrics were recorded.
+    pub metrics: Option<Metrics>,
+}
+
+/// What `.meta.yaml` files contain.
+#[derive(Deserialize)]
+struct FileStateCache {
+    states: Vec<FileState>,
+}
+
+/// Where the log is kept unless told otherwise.
+pub fn default_path() -> PathBuf {
+    let data_dir = match env::var_os("XDG_DATA_HOME") {
+        Some(ref dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(::shellexpand::tilde("~/.local/share").to_string()),
+    };
+    data_dir.join("encasa").join(LOG_NAME)
+}
+
+fn format_line(path: &str, snapshot: &Snapshot) -> String {
+    let time = snapshot.state.modification_time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    match snapshot.metrics {
+        Some(ref m) => format!("{}.{:09} {} {} {} {} {} {} {}\n", time.as_secs(), time.subsec_nanos(), snapshot.state.size,
+            m.words, m.lines, m.open_tasks, m.finished_tasks, m.bytes_added, path),
+        None => format!("{}.{:09} {} {}\n", time.as_secs(), time.subsec_nanos(), snapshot.state.size, path),
+    }
+}
+
+fn parse_line(line: &str) -> Option<(&str, Snapshot)> {
+    let mut parts = line.splitn(3, ' ');
+    let time = parts.next()?;
+    let size = parts.next()?.parse().ok()?;
+    let mut rest = parts.next()?;
+
+    let mut time = time.splitn(2, '.');
+    let secs = time.next()?.parse().ok()?;
+    let nanos = time.next().unwrap_or("0").parse().ok()?;
+    let state = FileState { modification_time: UNIX_EPOCH + Duration::new(secs, nanos), size };
+
+    // paths are canonical, so they never start with a number
+    let mut metrics = None;
+    let fields:Vec<&str> = rest.splitn(6, ' ').collect();
+    if fields.len() == 6 && fields[0].parse::<usize>().is_ok() {
+        metrics = Some(Metrics {
+            words: fields[0].parse().ok()?,
+            lines: fields[1].parse().ok()?,
+            open_tasks: fields[2].parse().ok()?,
+            finished_tasks: fields[3].parse().ok()?,
+            bytes_added: fields[4].parse().ok()?,
+        });
+        rest = fields[5];
+    }
+    Some((rest, Snapshot { state, metrics }))
+}
+
+/// The snapshots in a log, by path, oldest first.
+fn parse_log(contents: &str) -> HashMap<String, Vec<Snapshot>> {
+    let mut states:HashMap<String, Vec<Snapshot>> = HashMap::new();
+    for line in contents.lines().filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        match parse_line(line) {
+            Some((path, state)) => states.entry(path.to_string()).or_default().push(state),
+            None => eprintln!("skipping unreadable file history line `{}`", line),
+        }
+    }
+    states
+}
+
+/// What's in the `.meta.yaml` file that used to be kept next to `path`.
+fn read_sidecar(path: &str) -> Result<Vec<Snapshot>, ::failure::Error> {
+    let sidecar = format!("{}.meta.yaml", path);
+    if !Path::new(&sidecar).exists() {
+        return Ok(vec![]);
+    }
+    let cache:FileStateCache = ::serde_yaml::from_str(&::get_file_contents(&sidecar)?)
+        .map_err(|e| format_err!("could not read old file history {}: {}", sidecar, e))?;
+    println!("imported file history from {}; it isn't needed anymore", sidecar);
+    Ok(cache.states.into_iter().map(|state| Snapshot { state, metrics: None }).collect())
+}
+
+pub struct History {
+    path: PathBuf,
+    retention: Retention,
+}
+
+impl History {
+    pub fn new(path: &Path, retention: Retention) -> History {
+        History { path: path.to_path_buf(), retention }
+    }
+
+    /// The snapshots of the file at `path`, oldest first, after taking a
+    /// new one if it's changed. Tasks are only counted if it's a todo file,
+    /// with tasks in `tasks` format.
+    pub fn update(&self, path: &str, tasks: Option<FileFormat>) -> Result<Vec<Snapshot>, ::failure::Error> {
+        let current = FileState::from(&fs::metadata(path)?);
+        self.record(path, current, || Ok(Metrics::of(&String::from_utf8_lossy(&fs::read(path)?), tasks)))
+    }
+
+    /// Like `update`, for something that isn't read like a file, such as a
+    /// directory: `current` is its state now, and `metrics` measures it.
+    pub fn record<F>(&self, path: &str, current: FileState, metrics: F) -> Result<Vec<Snapshot>, ::failure::Error>
+        where F: FnOnce() -> Result<Metrics, ::failure::Error>
+    {
+        let key = fs::canonicalize(path).map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_else(|_| path.to_string());
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).context(format!("could not create data directory {}", dir.display()))?;
+        }
+        let log_path = self.path.to_string_lossy();
+        let log = LockedFile::open(&log_path)?;
+        let mut all = parse_log(&log.contents);
+
+        let mut added = vec![];
+        if !all.contains_key(&key) {
+            added.extend(read_sidecar(path)?);
+        }
+        let mut states = all.remove(&key).unwrap_or_default();
+        states.extend(added.iter().cloned());
+        if states.last().map(|s| &s.state) != Some(&current) {
+            let mut metrics = metrics()?;
+            if let Some(previous) = states.last() {
+                metrics.bytes_added = current.size as i64 - previous.state.size as i64;
+            }
+            let snapshot = Snapshot { state: current, metrics: Some(metrics) };
+            states.push(snapshot.clone());
+            added.push(snapshot);
+        }
+
+        if !added.is_empty() {
+            let lines:String = added.iter().map(|s| format_line(&key, s)).collect();
+            let mut file = OpenOptions::new().create(true).append(true).open(&self.path)
+                .context(format!("could not open file history {}", log_path))?;
+            file.write_all(lines.as_bytes())?;
+            file.sync_all()?;
+        }
+
+        all.insert(key, states.clone());
+        self.compact_if_needed(&log_path, all)?;
+        drop(log);
+
+        self.retention.apply(&mut states);
+        Ok(states)
+    }
+
+    /// Rewrites the log without the states that fall outside the retention
+    /// limits, once there are enough of them to be worth it.
+    fn compact_if_needed(&self, log_path: &str, mut all: HashMap<String, Vec<Snapshot>>) -> Result<(), ::failure::Error> {
+        let total:usize = all.values().map(Vec::len).sum();
+        for states in all.values_mut() {
+            self.retention.apply(states);
+        }
+        let kept:usize = all.values().map(Vec::len).sum();
+        if total - kept <= kept / 2 {
+            return Ok(());
+        }
+
+        let mut paths:Vec<&String> = all.keys().collect();
+        paths.sort();
+        let mut contents = String::new();
+        for path in paths {
+            for state in &all[path] {
+                contents.push_str(&format_line(path, state));
+            }
+        }
+        atomic::write_atomically(log_path, contents.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn records_imports_and_trims() {
+        let dir = ::tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("encasa").join(LOG_NAME);
+        let history = History::new(&log_path, Retention { keep_per_file: Some(3), keep_days: None });
+
+        let todo = dir.path().join("todo file.txt");
+        let todo = todo.to_str().unwrap();
+        File::create(todo).unwrap().write_all(b"x done\nsome task\n").unwrap();
+        File::create(format!("{}.meta.yaml", todo)).unwrap().write_all(b"states:
+  - modification_time: {secs_since_epoch: 1500000000, nanos_since_epoch: 0}
+    size: 10
+").unwrap();
+
+        let states = history.update(todo, Some(FileFormat::TodoTxt)).unwrap();
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0].state.size, 10);
+        assert_eq!(states[0].metrics, None);
+        assert_eq!(states[1].state.size, 17);
+        assert_eq!(states[1].metrics, Some(Metrics {
+            words: 4, lines: 2, open_tasks: 1, finished_tasks: 1, bytes_added: 7,
+        }));
+        // nothing changed, so nothing's added
+        assert_eq!(history.update(todo, Some(FileFormat::TodoTxt)).unwrap(), states);
+
+        for n in 0..6 {
+            File::create(todo).unwrap().write_all(&vec![b'x'; n]).unwrap();
+            let states = history.update(todo, None).unwrap();
+            assert_eq!(states.len(), 3);
+            assert_eq!(states[2].state.size, n as u64);
+        }
+        let lines = ::get_file_contents(log_path.to_str().unwrap()).unwrap().lines().count();
+        assert!(lines < 8, "{} lines left", lines);
+        for snapshot in &states {
+            assert_eq!(parse_log(&format_line("/a 1 2 3 4 5 b", snapshot))["/a 1 2 3 4 5 b"], vec![snapshot.clone()]);
+        }
+    }
+}
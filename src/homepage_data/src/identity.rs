@@ -0,0 +1,166 @@
+//! Identifying tasks across page loads.
+//!
+//! A task is identified by the file it lives in plus an anchor: the value of
+//! its `id:` tag if it has one, otherwise its line number. Separately, every
+//! task carries a version--a hash of its line--which the page sends back with
+//! any change. That lets us tell a task that merely moved (same version, new
+//! line) apart from one that was edited elsewhere since the page loaded.
+
+use std::str::FromStr;
+use std::fmt;
+
+use todo::Task;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct TaskId {
+    /// The expanded path of the todo file.
+    pub file: String,
+    /// Zero-based line number of the task when it was read.
+    pub line: usize,
+    /// The task's `id:` tag, or empty if it doesn't have one.
+    #[serde(default)] pub tag: String,
+}
+
+impl TaskId {
+    pub fn new(file: &str, line: usize, task: &Task) -> TaskId {
+        TaskId {
+            file: file.to_string(),
+            line,
+            tag: task.tags.get("id").cloned().unwrap_or_default(),
+        }
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.tag.is_empty() {
+            write!(f, "{}:{}", self.file, self.line + 1)
+        } else {
+            write!(f, "{}#{}", self.file, self.tag)
+        }
+    }
+}
+
+/// A short hash of a task's line, used to detect concurrent edits.
+///
+/// Versions are saved in the journal and with running timers, so this is
+/// 64-bit FNV-1a written out here rather than `DefaultHasher`, which can
+/// change from one Rust release to the next.
+pub fn content_version(line: &str) -> String {
+    let hash = line.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    format!("{:x}", hash)
+}
+
+#[derive(Debug)]
+pub enum TodoError {
+    /// There's no task with that id anymore.
+    NotFound { id: TaskId },
+    /// The task is still there, but it was changed since it was read.
+    Conflict { id: TaskId },
+    /// The new text for a task isn't a valid task.
+    Invalid { reason: String },
+}
+
+impl fmt::Display for TodoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TodoError::NotFound { ref id } =>
+                write!(f, "no todo at {}; reload the page to see its current contents", id),
+            TodoError::Conflict { ref id } =>
+                write!(f, "the todo at {} was changed since the page was loaded; reload and try again", id),
+            TodoError::Invalid { ref reason } =>
+                write!(f, "invalid todo: {}", reason),
+        }
+    }
+}
+
+impl ::failure::Fail for TodoError {}
+
+/// Finds the line index of the task identified by `id` at `version`.
+///
+/// Tasks with an `id:` tag are found wherever they are. Other tasks are
+/// looked for at their old line first, and then anywhere in the file with
+/// the same contents, preferring the closest match.
+pub fn locate<S: AsRef<str>>(lines: &[S], id: &TaskId, version: &str) -> Result<usize, TodoError> {
+    if !id.tag.is_empty() {
+        let found = lines.iter().position(|line| {
+            match Task::from_str(line.as_ref()) {
+                Ok(task) => task.tags.get("id") == Some(&id.tag),
+                Err(_) => false,
+            }
+        });
+        return match found {
+            Some(index) if content_version(lines[index].as_ref()) == version => Ok(index),
+            Some(_) => Err(TodoError::Conflict { id: id.clone() }),
+            None => Err(TodoError::NotFound { id: id.clone() }),
+        };
+    }
+
+    if id.line < lines.len() && content_version(lines[id.line].as_ref()) == version {
+        return Ok(id.line);
+    }
+
+    let distance = |index: usize| if index > id.line { index - id.line } else { id.line - index };
+    let moved = lines.iter()
+        .enumerate()
+        .filter(|&(_, line)| content_version(line.as_ref()) == version)
+        .map(|(index, _)| index)
+        .min_by_key(|&index| distance(index));
+
+    match moved {
+        Some(index) => Ok(index),
+        None if id.line < lines.len() => Err(TodoError::Conflict { id: id.clone() }),
+        None => Err(TodoError::NotFound { id: id.clone() }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(line: usize, tag: &str) -> TaskId {
+        TaskId { file: "todo.txt".into(), line, tag: tag.into() }
+    }
+
+    #[test]
+    fn versions_stay_the_same() {
+        assert_eq!(content_version(""), "cbf29ce484222325");
+        assert_eq!(content_version("a"), "af63dc4c8601ec8c");
+        assert_eq!(content_version("foobar"), "85944171f73967e8");
+    }
+
+    #[test]
+    fn locate_moved_and_changed_tasks() {
+        let before = ["buy milk", "call bob"];
+        let version = content_version(before[1]);
+
+        assert_eq!(locate(&before, &id(1, ""), &version).ok(), Some(1));
+
+        let moved = ["new task", "buy milk", "call bob"];
+        assert_eq!(locate(&moved, &id(1, ""), &version).ok(), Some(2));
+
+        match locate(&["buy milk", "call bob @phone"], &id(1, ""), &version) {
+            Err(TodoError::Conflict { .. }) => {},
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+
+        match locate(&["buy milk"], &id(1, ""), &version) {
+            Err(TodoError::NotFound { .. }) => {},
+            other => panic!("expected not found, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn locate_by_id_tag() {
+        let lines = ["first", "(A) write report id:report"];
+        let version = content_version(lines[1]);
+        assert_eq!(locate(&lines, &id(7, "report"), &version).ok(), Some(1));
+
+        match locate(&["(B) write report id:report"], &id(1, "report"), &version) {
+            Err(TodoError::Conflict { .. }) => {},
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,358 @@
+//! Undoing and redoing changes made from the page.
+//!
+//! Each change we make to todo files is recorded in a journal with the lines
+//! it replaced in every file it touched, and what it replaced them with.
+//! Undoing an entry puts the old lines back and redoing it puts the new ones
+//! back, but only while the file still reads exactly as the entry left it,
+//! so that edits made elsewhere in the meantime are never thrown away.
+//! Files that were moved, like a finished item of a directory, are moved
+//! back and forth the same way.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use atomic::LockedFile;
+use backups::Operation;
+use identity::content_version;
+
+/// How many entries are kept for undoing, and for redoing.
+const MAX_ENTRIES: usize = 50;
+
+/// A change to one file, kept as the run of lines that changed rather than
+/// the whole file. Changes recorded when whole files were kept read with
+/// empty versions, which no file matches, so they just can't be undone.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct FileChange {
+    pub path: String,
+    /// Whether the change created the file.
+    pub created: bool,
+    /// Hashes of the whole file before and after the change.
+    pub before_version: String,
+    pub after_version: String,
+    /// The index of the first line that changed.
+    pub start: usize,
+    /// The lines, with their line endings, that the change took out.
+    pub removed: Vec<String>,
+    /// The lines it put in their place.
+    pub added: Vec<String>,
+}
+
+fn lines(contents: &str) -> Vec<&str> {
+    contents.split_inclusive('\n').collect()
+}
+
+impl FileChange {
+    /// The change from `before`, or from no file at all, to `after`.
+    pub fn new(path: &str, before: Option<&str>, after: &str) -> FileChange {
+        let old = lines(before.unwrap_or(""));
+        let new = lines(after);
+        let start = old.iter().zip(new.iter()).take_while(|&(a, b)| a == b).count();
+        let end = old[start..].iter().rev().zip(new[start..].iter().rev()).take_while(|&(a, b)| a == b).count();
+        FileChange {
+            path: path.to_string(),
+            created: before.is_none(),
+            before_version: content_version(before.unwrap_or("")),
+            after_version: content_version(after),
+            start,
+            removed: old[start..old.len() - end].iter().map(|l| l.to_string()).collect(),
+            added: new[start..new.len() - end].iter().map(|l| l.to_string()).collect(),
+        }
+    }
+
+    /// Writes `contents` to `file`, returning the change for the journal.
+    pub fn write(file: &mut LockedFile, contents: &str) -> Result<FileChange, ::failure::Error> {
+        let before = match file.state {
+            Some(_) => Some(file.contents.clone()),
+            None => None,
+        };
+        file.write(contents)?;
+        Ok(FileChange::new(&file.path, before.as_deref(), contents))
+    }
+
+    /// What `contents` becomes when the change is undone or redone, or
+    /// `None` if `contents` isn't what the file looked like on that side of
+    /// the change.
+    fn replay(&self, contents: &str, undo: bool) -> Option<String> {
+        let (version, from, to) = if undo {
+            (&self.after_version, &self.added, &self.removed)
+        } else {
+            (&self.before_version, &self.removed, &self.added)
+        };
+        let lines = lines(contents);
+        let end = self.start + from.len();
+        if content_version(contents) != *version || end > lines.len() || lines[self.start..end] != from[..] {
+            return None;
+        }
+        let mut replayed = lines[..self.start].concat();
+        replayed.push_str(&to.concat());
+        replayed.push_str(&lines[end..].concat());
+        Some(replayed)
+    }
+}
+
+/// A file that a change moved from `from` to `to`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileMove {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub operation: Operation,
+    pub time: SystemTime,
+    pub changes: Vec<FileChange>,
+    #[serde(default)] pub moves: Vec<FileMove>,
+}
+
+impl Entry {
+    pub fn new(operation: Operation, changes: Vec<FileChange>) -> Entry {
+        Entry { operation, time: SystemTime::now(), changes, moves: vec![] }
+    }
+
+    /// An entry for moving the file at `from` to `to`.
+    pub fn moved(operation: Operation, from: &str, to: &str) -> Entry {
+        let moves = vec![FileMove { from: from.to_string(), to: to.to_string() }];
+        Entry { operation, time: SystemTime::now(), changes: vec![], moves }
+    }
+
+    /// A short description for telling the user what was undone.
+    pub fn describe(&self) -> String {
+        let files:Vec<String> = self.changes.iter()
+            .map(|c| &c.path)
+            .chain(self.moves.iter().map(|m| &m.from))
+            .map(|path| Path::new(path).file_name()
+                 .map(|n| n.to_string_lossy().to_string())
+                 .unwrap_or_else(|| path.clone()))
+            .collect();
+        format!("{} in {}", self.operation, files.join(", "))
+    }
+}
+
+#[derive(Debug)]
+pub enum JournalError {
+    NothingToUndo,
+    NothingToRedo,
+    /// The file was changed since the entry was recorded, so it can't be
+    /// undone or redone without losing that change.
+    Changed { path: String },
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JournalError::NothingToUndo => write!(f, "there's nothing to undo"),
+            JournalError::NothingToRedo => write!(f, "there's nothing to redo"),
+            JournalError::Changed { ref path } =>
+                write!(f, "{} was changed since then, so it can't be done safely; use /backups instead", path),
+        }
+    }
+}
+
+impl ::failure::Fail for JournalError {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Journal {
+    #[serde(default)] pub done: Vec<Entry>,
+    #[serde(default)] pub undone: Vec<Entry>,
+}
+
+fn trim(entries: &mut Vec<Entry>) {
+    if entries.len() > MAX_ENTRIES {
+        let extra = entries.len() - MAX_ENTRIES;
+        entries.drain(..extra);
+    }
+}
+
+impl Journal {
+    fn load(file: &LockedFile) -> Result<Journal, ::failure::Error> {
+        if file.state.is_none() {
+            return Ok(Journal::default());
+        }
+        Ok(::serde_yaml::from_str(&file.contents)
+            .map_err(|e| format_err!("could not read the undo journal {}: {}", file.path, e))?)
+    }
+
+    fn save(&mut self, file: &mut LockedFile) -> Result<(), ::failure::Error> {
+        trim(&mut self.done);
+        trim(&mut self.undone);
+        let yaml = ::serde_yaml::to_string(self)
+            .map_err(|e| format_err!("could not convert the undo journal to YAML: {}", e))?;
+        file.write(&yaml)
+    }
+}
+
+/// Records a change that can be undone. Anything that was undone before
+/// can't be redone anymore.
+pub fn record(path: &str, entry: Entry) -> Result<(), ::failure::Error> {
+    let mut file = LockedFile::open(path)?;
+    let mut journal = Journal::load(&file)?;
+    journal.done.push(entry);
+    journal.undone.clear();
+    journal.save(&mut file)
+}
+
+/// Moves every file in `entry` from one side of the change to the other,
+/// backing up what's replaced.
+fn apply(entry: &Entry, undo: bool, backups: &::backups::Backups) -> Result<(), ::failure::Error> {
+    let changes = &entry.changes;
+    let moves:Vec<(&str, &str)> = entry.moves.iter()
+        .map(|m| if undo { (m.to.as_str(), m.from.as_str()) } else { (m.from.as_str(), m.to.as_str()) })
+        .collect();
+    for &(from, to) in &moves {
+        if !Path::new(from).exists() || Path::new(to).exists() {
+            return Err(JournalError::Changed { path: from.to_string() }.into());
+        }
+    }
+
+    // lock everything first, so we either change all of the files or none
+    let mut files = vec![];
+    for change in changes {
+        let file = LockedFile::open(&change.path)?;
+        // only a file the change created is missing before it
+        let should_exist = undo || !change.created;
+        let replayed = if file.state.is_some() == should_exist {
+            change.replay(&file.contents, undo)
+        } else {
+            None
+        };
+        match replayed {
+            Some(contents) => files.push((file, contents)),
+            None => return Err(JournalError::Changed { path: change.path.clone() }.into()),
+        }
+    }
+
+    let operation = if undo { Operation::Undo } else { Operation::Redo };
+    for (change, &mut (ref mut file, ref contents)) in changes.iter().zip(files.iter_mut()) {
+        if file.state.is_some() {
+            backups.save(&change.path, &file.contents, operation)?;
+        }
+        if undo && change.created {
+            file.remove()?;
+        } else {
+            file.write(contents)?;
+        }
+    }
+    for (from, to) in moves {
+        fs::rename(from, to).map_err(|e| format_err!("could not move {} to {}: {}", from, to, e))?;
+        ::store::mark_stale();
+    }
+    Ok(())
+}
+
+/// Undoes or redoes the last entry, and moves it to the other stack.
+///
+/// The journal isn't locked while the todo files are changed, since writes
+/// lock a todo file first and then the journal; so the entry is looked up
+/// again afterwards, in case something else was recorded in the meantime.
+fn shift(path: &str, undo: bool, backups: &::backups::Backups) -> Result<Entry, ::failure::Error> {
+    let entry = {
+        let file = LockedFile::open(path)?;
+        let journal = Journal::load(&file)?;
+        let from = if undo { &journal.done } else { &journal.undone };
+        match from.last() {
+            Some(entry) => entry.clone(),
+            None if undo => return Err(JournalError::NothingToUndo.into()),
+            None => return Err(JournalError::NothingToRedo.into()),
+        }
+    };
+
+    apply(&entry, undo, backups)?;
+
+    let mut file = LockedFile::open(path)?;
+    let mut journal = Journal::load(&file)?;
+    {
+        let (from, to) = if undo {
+            (&mut journal.done, &mut journal.undone)
+        } else {
+            (&mut journal.undone, &mut journal.done)
+        };
+        if let Some(index) = from.iter().rposition(|e| *e == entry) {
+            from.remove(index);
+        }
+        to.push(entry.clone());
+    }
+    journal.save(&mut file)?;
+    Ok(entry)
+}
+
+/// Undoes the most recent change. Returns the entry that was undone.
+pub fn undo(path: &str, backups: &::backups::Backups) -> Result<Entry, ::failure::Error> {
+    shift(path, true, backups)
+}
+
+/// Redoes the most recently undone change. Returns the entry that was redone.
+pub fn redo(path: &str, backups: &::backups::Backups) -> Result<Entry, ::failure::Error> {
+    shift(path, false, backups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+    use backups::{Backups, Retention};
+
+    #[test]
+    fn undo_and_redo() {
+        let dir = ::tempfile::tempdir().unwrap();
+        let journal = dir.path().join("journal.yaml");
+        let journal = journal.to_str().unwrap();
+        let todo = dir.path().join("todo.txt");
+        let todo = todo.to_str().unwrap();
+        let done = dir.path().join("done.txt");
+        let done = done.to_str().unwrap();
+        let backups = Backups::new(dir.path().join("backups").to_str().unwrap(), Retention::default());
+
+        File::create(todo).unwrap().write_all(b"call bob\nbuy milk\n").unwrap();
+        File::create(done).unwrap().write_all(b"x old\n").unwrap();
+        let change = FileChange::new(todo, Some("x call bob\nbuy milk\n"), "call bob\nbuy milk\n");
+        assert_eq!((change.start, change.removed.clone(), change.added.clone()),
+                   (0, vec!["x call bob\n".to_string()], vec!["call bob\n".to_string()]));
+        record(journal, Entry::new(Operation::Archive, vec![change, FileChange::new(done, None, "x old\n")])).unwrap();
+
+        let entry = undo(journal, &backups).unwrap();
+        assert_eq!(entry.describe(), "archive in todo.txt, done.txt");
+        assert_eq!(::get_file_contents(todo).unwrap(), "x call bob\nbuy milk\n");
+        assert!(!::std::path::Path::new(done).exists());
+        assert!(undo(journal, &backups).unwrap_err().downcast::<JournalError>().is_ok());
+
+        redo(journal, &backups).unwrap();
+        assert_eq!(::get_file_contents(todo).unwrap(), "call bob\nbuy milk\n");
+        assert_eq!(::get_file_contents(done).unwrap(), "x old\n");
+
+        // something else changed the file, so undoing would lose that
+        File::create(todo).unwrap().write_all(b"call bob\nbuy milk\nbuy eggs\n").unwrap();
+        match undo(journal, &backups).unwrap_err().downcast::<JournalError>() {
+            Ok(JournalError::Changed { path }) => assert_eq!(path, todo),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(::get_file_contents(todo).unwrap(), "call bob\nbuy milk\nbuy eggs\n");
+    }
+
+    #[test]
+    fn undo_a_move() {
+        let dir = ::tempfile::tempdir().unwrap();
+        let journal = dir.path().join("journal.yaml");
+        let journal = journal.to_str().unwrap();
+        let backups = Backups::new(dir.path().join("backups").to_str().unwrap(), Retention::default());
+        let from = dir.path().join("paper.pdf");
+        let to = dir.path().join("paper read.pdf");
+
+        File::create(&to).unwrap();
+        record(journal, Entry::moved(Operation::Complete, from.to_str().unwrap(), to.to_str().unwrap())).unwrap();
+
+        assert_eq!(undo(journal, &backups).unwrap().describe(), "complete in paper.pdf");
+        assert!(from.exists() && !to.exists());
+        redo(journal, &backups).unwrap();
+        assert!(!from.exists() && to.exists());
+
+        // something else is in the way of moving it back
+        File::create(&from).unwrap();
+        assert!(undo(journal, &backups).is_err());
+        assert!(to.exists());
+    }
+}
@@ -8,31 +8,61 @@ extern crate serde_json;
 extern crate serde_yaml;
 extern crate shellexpand;
 extern crate humantime;
+extern crate fs2;
+extern crate yaml_rust;
+extern crate notify;
+extern crate glob;
+extern crate git2;
 #[macro_use] extern crate failure;
 
 #[macro_use] extern crate lazy_static;
 #[macro_use] extern crate nom;
 extern crate regex;
 
+pub mod atomic;
+pub mod config;
+pub mod backups;
+pub mod history;
+pub mod goals;
+pub mod directory;
+pub mod git;
+pub mod sources;
+pub mod markdown;
+pub mod journal;
 pub mod todo;
 pub mod gcal;
 pub mod datetools;
+pub mod recurrence;
+pub mod identity;
+pub mod edit;
+pub mod diagnostics;
+pub mod tree;
+pub mod deps;
+pub mod timetrack;
+pub mod tokens;
+pub mod store;
 
 pub use chrono::NaiveDate as Date;
 
 use todo::Task;
+use identity::{TaskId, TodoError};
+use edit::TodoFile;
+use atomic::LockedFile;
+use diagnostics::Diagnostic;
 use std::str::FromStr;
+use std::collections::HashSet;
 use std::fs;
-use std::fs::{File, OpenOptions};
+use std::fs::File;
 use std::path::{Path};
-use std::io::{Read, Write, BufReader, BufRead};
+use std::io::{Read, BufReader};
 use std::time::SystemTime;
 use std::process::Command;
 
 use failure::{ResultExt};
 
-static META_YAML_PATH: &str = "~/homepage.yaml";
-static DEADLINES_JSON_PATH: &str = "~/deadlines.json";
+static TIMERS_PATH: &str = "~/.homepage/timers.yaml";
+static BACKUPS_PATH: &str = "~/.homepage/backups";
+static JOURNAL_PATH: &str = "~/.homepage/journal.yaml";
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct FileState
@@ -42,40 +72,118 @@ pub struct FileState
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 struct HomepageMeta {
     local: Vec<LocalFileDesc>,
+    #[serde(default)] backups: backups::Retention,
+    #[serde(default)] history: history::Retention,
+    /// Where `update_deadlines` leaves upcoming calendar events.
+    #[serde(default = "default_deadlines_path")] deadlines: String,
+}
+
+fn default_deadlines_path() -> String {
+    "~/deadlines.json".to_string()
 }
 
 impl HomepageMeta {
+    /// The config, with any globs in `local` expanded.
     pub fn from_local_config() -> Result<HomepageMeta, failure::Error> {
-        let path = shellexpand::tilde(META_YAML_PATH);
-        Ok(serde_yaml::from_str(&get_file_contents(&path)?)
-            .expect(&format!("Couldn't parse YAML at {}", path)))
+        let mut meta = config::load()?;
+        meta.local = sources::expand(&meta.local);
+        Ok(meta)
     }
 }
 
-fn seconds_from_humantime(s: &str) -> i64 {
-    let duration = humantime::parse_duration(&s).unwrap();
+fn local_date(time: SystemTime) -> Date {
+    chrono::DateTime::<chrono::Local>::from(time).naive_local().date()
+}
+
+fn seconds_from_humantime(s: &str) -> Result<i64, humantime::DurationError> {
+    let duration = humantime::parse_duration(&s)?;
     let seconds = duration.as_secs();
-    seconds as i64
+    Ok(seconds as i64)
 }
 
-use serde::de::{Deserialize, Deserializer};
+use serde::de::{Deserialize, Deserializer, Error};
 fn deserialize_humantime<'a, D>(deserializer: D) -> std::result::Result<i64, D::Error>
     where D: Deserializer<'a>
 {
     let s = String::deserialize(deserializer)?;
-    Ok(seconds_from_humantime(&s))
+    seconds_from_humantime(&s)
+        .map_err(|e| D::Error::custom(format!("`{}` isn't a duration: {}", s, e)))
+}
+
+/// What a `local:` entry's `path` is.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    File,
+    /// A folder whose files are listed; see `directory`.
+    Directory,
+}
+
+impl Default for SourceKind {
+    fn default() -> SourceKind {
+        SourceKind::File
+    }
+}
+
+/// How the tasks in a todo file are written.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    TodoTxt,
+    /// Checklists in Markdown; see `markdown`.
+    Markdown,
+}
+
+impl Default for FileFormat {
+    fn default() -> FileFormat {
+        FileFormat::TodoTxt
+    }
+}
+
+/// What archiving does with a todo file's finished tasks.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveMode {
+    /// Takes them out of the file: into its done.txt for todo.txt files, and
+    /// nowhere for Markdown ones (there's still the backup).
+    Remove,
+    Keep,
+}
+
+impl Default for ArchiveMode {
+    fn default() -> ArchiveMode {
+        ArchiveMode::Remove
+    }
+}
+
+fn default_done_dir() -> String {
+    "done".to_string()
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct LocalFileDesc {
     #[serde(default)] pub name: String,
 
     pub path: String,
 
+    #[serde(default)] pub kind: SourceKind,
+
+    /// Which files in a directory to list, like `*.pdf`; all of them if empty.
+    #[serde(default)] pub glob: String,
+
+    /// Where in a directory completed items are moved to.
+    #[serde(default = "default_done_dir")] pub done_dir: String,
+
     #[serde(default)] pub todos: bool,
 
+    #[serde(default)] pub format: FileFormat,
+
+    #[serde(default)] pub archive: ArchiveMode,
+
     #[serde(default)]
     #[serde(deserialize_with = "deserialize_humantime")]
     pub frequency_goal_seconds: i64,
@@ -85,6 +193,16 @@ pub struct LocalFileDesc {
     #[serde(default)] pub hide_in_index: bool,
 
     #[serde(default)] pub git: String,
+
+    /// Read relative `due:` and `t:` dates (`due:fri`) written by other
+    /// tools, as of the day the file was last modified.
+    #[serde(default)] pub relative_dates: bool,
+
+    /// How many words the file should grow by each day; see `goals`.
+    #[serde(default)] pub words_per_day: Option<usize>,
+
+    /// How many open tasks the file should have at most.
+    #[serde(default)] pub open_tasks_max: Option<usize>,
 }
 
 impl LocalFileDesc {
@@ -92,6 +210,29 @@ impl LocalFileDesc {
         shellexpand::tilde(&self.path).to_string()
     }
 
+    /// The day that relative dates in the file are relative to, if they
+    /// should be read at all.
+    pub fn relative_dates_base(&self) -> Option<Date> {
+        if !self.relative_dates {
+            return None;
+        }
+        let modified = fs::metadata(self.expanded_path()).and_then(|md| md.modified()).ok()?;
+        Some(local_date(modified))
+    }
+
+    /// The format of the file's tasks, if it's a todo file.
+    pub fn task_format(&self) -> Option<FileFormat> {
+        if self.todos {
+            Some(self.format)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.kind == SourceKind::Directory
+    }
+
     pub fn readable_name(&self) -> &str {
         if self.name.is_empty() {
             &self.path
@@ -101,11 +242,6 @@ impl LocalFileDesc {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct FileStateCache {
-    states: Vec<FileState>,
-}
-
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum UpdateState {
     NoGoal,
@@ -116,9 +252,18 @@ pub enum UpdateState {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LocalFileDescWithState {
     pub desc: LocalFileDesc,
-    pub states: Vec<FileState>,
+    /// Snapshots of the file, oldest first.
+    pub history: Vec<history::Snapshot>,
     pub update_state: UpdateState,
+    /// What's keeping the file's goals from being met.
+    #[serde(default)] pub unmet_goals: Vec<String>,
+    /// How well the file keeps up with `frequency_goal_seconds`, if it has one.
+    #[serde(default)] pub streaks: Option<goals::Streaks>,
     pub file_is_showing_todos: bool, // TODO this will go away once the view crate is doing the filtering
+    /// What's going on in the file's `git` repo, if it could be read.
+    #[serde(default)] pub git: Option<git::RepoStatus>,
+    /// The files listed, if it's a directory.
+    #[serde(default)] pub items: Vec<directory::DirectoryItem>,
 }
 
 fn run_shell_command(command: &str, working_dir: &str) -> String {
@@ -154,29 +299,21 @@ pub fn update_deadlines() -> Result<(), failure::Error> {
     }
 }
 
-fn get_last_commit_date(working_dir: &str) -> std::time::SystemTime {
-    // '%at': author date, UNIX timestamp
-    // '%aI': author date, strict ISO 8601 format
-    let git_command = "git log -1 --pretty=format:%at";
-    let s = run_shell_command(git_command, working_dir);
-    let secs:u64 = s.parse().unwrap();
-    let duration = std::time::Duration::new(secs, 0);
-    SystemTime::UNIX_EPOCH + duration
-}
-
 impl LocalFileDescWithState {
     pub fn last_modified(&self) -> SystemTime {
-        if !self.desc.git.is_empty() {
-            get_last_commit_date(&shellexpand::tilde(&self.desc.git).to_string())
+        if let Some(last_commit) = self.git.as_ref().and_then(|g| g.last_commit()) {
+            last_commit
         } else {
-            assert!(!&self.states.is_empty());
-            let last_state = &self.states[&self.states.len() - 1];
+            assert!(!&self.history.is_empty());
+            let last_state = &self.history[&self.history.len() - 1].state;
             last_state.modification_time
         }
     }
 
+    /// Zero for a commit dated in the future, which a skewed clock or a
+    /// rebase can make.
     pub fn duration_since_modified(&self) -> std::time::Duration {
-        SystemTime::now().duration_since(self.last_modified()).unwrap()
+        SystemTime::now().duration_since(self.last_modified()).unwrap_or_default()
     }
 
     pub fn needs_update(&self) -> bool {
@@ -185,12 +322,26 @@ impl LocalFileDescWithState {
             _ => false,
         }
     }
+
+    pub fn unmet_goals_text(&self) -> String {
+        self.unmet_goals.join("; ")
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct TaskWithContext {
+    pub id:TaskId,
+    pub version:String,
     pub task:Task,
     pub auto_project:String,
+    /// Tasks nested under this one, by indentation or a `parent:` tag.
+    #[serde(default)] pub subtasks:Vec<TaskWithContext>,
+    /// The ids of the unfinished tasks that this one depends on.
+    #[serde(default)] pub blocked_by:Vec<String>,
+    /// Whether a timer is running for this task.
+    #[serde(default)] pub timer_running:bool,
+    /// Mistakes in the task's line, which was read as well as it could be.
+    #[serde(default)] pub warnings:Vec<Diagnostic>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -200,6 +351,13 @@ pub struct CachedData {
     pub todos: Vec<TaskWithContext>,
     pub local_files: Vec<LocalFileDescWithState>,
     pub deadlines: Deadlines,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Estimates and time spent, by `+project`.
+    pub project_time: Vec<timetrack::TimeRollup>,
+    /// Estimates and time spent, by file.
+    pub file_time: Vec<timetrack::TimeRollup>,
+    /// Timers running for tasks that can't be found anymore.
+    pub orphaned_timers: Vec<timetrack::RunningTimer>,
 }
 
 
@@ -244,254 +402,640 @@ fn get_file_contents(path: &str) -> Result<String, failure::Error> {
     Ok(contents)
 }
 
-fn parse_todo_file(path: &str) -> Result<Vec<Task>, failure::Error> {
-    let mut tasks:Vec<Task> = vec!();
-    let f = File::open(path).context(format!("missing todo file {}", path))?;
-    for (num, line) in BufReader::new(f).lines().enumerate() {
-        match Task::from_str(&line?) {
-            Ok(task) => {
-                if !task.subject.is_empty() {
-                    tasks.push(task);
-                }
-            },
-            Err(_) => {
-                eprintln!("ERROR parsing todo in {}:{}", path, num);
+fn read_todo_file(path: &str) -> Result<TodoFile, failure::Error> {
+    let contents = get_file_contents(path).context(format!("missing todo file {}", path))?;
+    Ok(TodoFile::parse(&contents))
+}
+
+/// Reads the task in `text`, which is line `index` of `path` from `offset`
+/// on, with any relative dates resolved. Mistakes in it are added to
+/// `diagnostics` and to the task, which is read as well as it can be; only a
+/// line that can't be read as a task at all is left out.
+fn read_task_line(path: &str, index: usize, line: &str, text: &str, offset: usize, auto_project: &str, diagnostics: &mut Vec<Diagnostic>) -> Option<TaskWithContext> {
+    let warning = todo::check(text).err().map(|e| Diagnostic::parse_error(path, index, offset, &e));
+    diagnostics.extend(warning.clone());
+    match Task::from_str(text) {
+        Ok(ref task) if task.subject.is_empty() => None,
+        Ok(task) => {
+            let mut todo = TaskWithContext::new(path, index, line, task, auto_project);
+            todo.warnings.extend(warning);
+            Some(todo)
+        },
+        Err(e) => {
+            if warning.is_none() {
+                diagnostics.push(Diagnostic::parse_error(path, index, offset, &e));
             }
+            None
+        },
+    }
+}
+
+/// Reads the tasks in a todo file. With `relative_to`, relative dates are
+/// read as of that day; otherwise they're reported as invalid.
+fn parse_todo_file(path: &str, auto_project: &str, relative_to: Option<Date>, diagnostics: &mut Vec<Diagnostic>) -> Result<Vec<TaskWithContext>, failure::Error> {
+    let mut tasks:Vec<(usize, TaskWithContext)> = vec!();
+    for (num, line) in read_todo_file(path)?.texts().iter().enumerate() {
+        let resolved = relative_to.map(|base| edit::resolve_relative_dates(line, &base));
+        let text = resolved.as_ref().map(|s| s.as_str()).unwrap_or(line);
+        if let Some(todo) = read_task_line(path, num, line, text, 0, auto_project, diagnostics) {
+            tasks.push((todo::spans(line).indent, todo));
         }
     }
 
-    Ok(tasks)
+    Ok(tree::nest_by_indent(tasks))
 }
 
-fn archive_tasks_in_todo_file(path: &str) -> Result<u32, failure::Error> {
-    let mut lines:Vec<String> = vec![];
-    let mut done_lines:Vec<String> = vec![];
-    for line in get_file_contents(path)?.lines() {
-        match &mut Task::from_str(&line) {
-            Ok(task) if task.finished => {
-                done_lines.push(line.into());
-            }
-            _ => {
-                lines.push(line.into());
+/// Reads the tasks in a configured todo file, whatever its format.
+fn read_tasks(local_file: &LocalFileDesc, diagnostics: &mut Vec<Diagnostic>) -> Result<Vec<TaskWithContext>, failure::Error> {
+    let path = local_file.expanded_path();
+    match local_file.format {
+        FileFormat::TodoTxt => parse_todo_file(&path, &local_file.auto_project, local_file.relative_dates_base(), diagnostics),
+        FileFormat::Markdown => {
+            let contents = get_file_contents(&path).context(format!("missing todo file {}", path))?;
+            Ok(markdown::parse(&path, &contents, &local_file.auto_project, local_file.relative_dates_base(), diagnostics))
+        },
+    }
+}
+
+/// Moves finished tasks into the file's done.txt, adding the files it
+/// changed to `changes`. Tasks whose `id:` is in `unfinished` have subtasks
+/// in other files that aren't finished, so they stay.
+fn archive_tasks_in_todo_file(storage: &Storage, path: &str, relative_dates: bool, unfinished: &HashSet<String>, changes: &mut Vec<journal::FileChange>) -> Result<u32, failure::Error> {
+    let mut file = LockedFile::open(path)?;
+    let mut todo_file = pin_relative_dates(&mut file, relative_dates);
+    let mut done_lines:Vec<edit::Line> = vec![];
+    let mut index = 0;
+    while index < todo_file.lines.len() {
+        // a finished task only goes once all of its subtasks are finished too
+        let is_finished = |l: &edit::Line| match Task::from_str(&l.text) {
+            Ok(t) => t.finished && t.tags.get("id").into_iter().all(|id| !unfinished.contains(id)),
+            Err(_) => false,
+        };
+        let end = tree::subtree_end(&todo_file.texts(), index);
+        let subtree_finished = is_finished(&todo_file.lines[index]) &&
+            todo_file.lines[index..end].iter()
+                .filter(|l| !l.text.trim().is_empty())
+                .all(|l| is_finished(l));
+        if subtree_finished {
+            let mut removed = vec![];
+            for j in (index..end).rev() {
+                if !todo_file.lines[j].text.trim().is_empty() {
+                    removed.push(todo_file.remove_line(j));
+                }
             }
+            done_lines.extend(removed.into_iter().rev());
+        } else {
+            index += 1;
         }
     }
 
     if !done_lines.is_empty() {
         // append done lines
-        let mut f = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(get_done_filename(path)
-                  .expect(&format!("couldn't make a done.txt path for {}", path)))?;
+        let done_path = get_done_filename(path)
+            .expect(&format!("couldn't make a done.txt path for {}", path));
+        let mut done = LockedFile::open(&done_path)?;
+        if done.state.is_some() {
+            storage.backups.save(&done_path, &done.contents, backups::Operation::Archive)?;
+        }
+        storage.backups.save(path, &file.contents, backups::Operation::Archive)?;
 
-        f.write_all(&String::from("\n").into_bytes())?;
-        f.write_all(&done_lines.join("\n").into_bytes())?;
+        let done_file = if done.state.is_some() {
+            let mut done_file = TodoFile::parse(&done.contents);
+            for line in &done_lines {
+                done_file.push_line(line.text.clone());
+            }
+            done_file
+        } else {
+            TodoFile { bom: false, lines: done_lines.clone() }
+        };
+        // the tasks mustn't end up in done.txt without leaving the todo file
+        file.check_unchanged()?;
+        done.check_unchanged()?;
+        changes.push(journal::FileChange::write(&mut done, &done_file.to_string())?);
 
         // rewrite todo file
-        File::create(&path)?.write_all(&lines.join("\n").into_bytes())?;
+        changes.push(journal::FileChange::write(&mut file, &todo_file.to_string())?);
     }
 
     Ok(done_lines.len() as u32)
 }
 
+/// Takes the finished items out of a Markdown file, adding it to `changes`
+/// if that changed it.
+fn archive_tasks_in_markdown_file(storage: &Storage, path: &str, relative_dates: bool, changes: &mut Vec<journal::FileChange>) -> Result<u32, failure::Error> {
+    let mut file = LockedFile::open(path)?;
+    let mut todo_file = pin_relative_dates(&mut file, relative_dates);
+    let removed = markdown::remove_finished(&mut todo_file);
+    if removed > 0 {
+        storage.backups.save(path, &file.contents, backups::Operation::Archive)?;
+        changes.push(journal::FileChange::write(&mut file, &todo_file.to_string())?);
+    }
+    Ok(removed as u32)
+}
+
 pub fn archive_finished_tasks() -> Result<u32, failure::Error> {
+    let meta = HomepageMeta::from_local_config()?;
+    archive_files(&Storage::from_local_config(), &meta.local)
+}
+
+fn archive_files(storage: &Storage, local_files: &[LocalFileDesc]) -> Result<u32, failure::Error> {
+    let todo_files:Vec<&LocalFileDesc> = local_files.iter().filter(|f| f.todos).collect();
+    // a file that can't be read has no subtasks to wait for
+    let all_todos = todo_files.iter()
+        .filter_map(|f| read_tasks(f, &mut vec![]).ok())
+        .flatten()
+        .collect();
+    let unfinished = tree::unfinished_ids(&tree::attach_tagged_subtasks(all_todos));
+
     let mut count:u32 = 0;
-    for ref local_file in HomepageMeta::from_local_config()?.local.iter().filter(|&f| f.todos) {
+    let mut changes = vec![];
+    let mut archived = Ok(());
+    for local_file in todo_files.into_iter().filter(|f| f.archive == ArchiveMode::Remove) {
         let path:&str = &shellexpand::tilde(&local_file.path);
-        count += archive_tasks_in_todo_file(path)?;
+        let result = match local_file.format {
+            FileFormat::TodoTxt => archive_tasks_in_todo_file(storage, path, local_file.relative_dates, &unfinished, &mut changes),
+            FileFormat::Markdown => archive_tasks_in_markdown_file(storage, path, local_file.relative_dates, &mut changes),
+        };
+        match result {
+            Ok(n) => count += n,
+            Err(e) => {
+                archived = Err(e);
+                break;
+            },
+        }
     }
+    // the files archived before an error are changed all the same, so they
+    // still need to be undoable
+    storage.record(backups::Operation::Archive, changes);
+    archived.map(|_| count)
+}
 
-    Ok(count)
+/// The result of marking a todo finished or unfinished.
+#[derive(Serialize, Debug, Clone)]
+pub struct TodoUpdate {
+    /// The task's id, which changes if the task had moved within its file.
+    pub id: TaskId,
+    pub version: String,
+    /// A fresh copy of a task with a `rec:` tag that was just completed.
+    pub recurrence: Option<TaskWithContext>,
+    /// The unfinished parent of a task whose subtasks are now all finished,
+    /// which the user may want to finish too.
+    pub parent: Option<TaskWithContext>,
+    /// Tasks that depend on this one, with their `blocked_by` updated.
+    pub dependents: Vec<TaskWithContext>,
 }
 
-pub fn mark_todo_completed(hash: &str, finished: bool) -> Result<String, failure::Error> {
-    let meta = HomepageMeta::from_local_config()?;
-    let mut found_any = false;
-    let mut new_hash:String = String::new();
+/// The backup store, with the retention limits from the config file.
+pub fn backup_store() -> backups::Backups {
+    let retention = HomepageMeta::from_local_config()
+        .map(|meta| meta.backups)
+        .unwrap_or_default();
+    backups::Backups::new(&shellexpand::tilde(BACKUPS_PATH), retention)
+}
 
-    for ref local_file in meta.local.iter().filter(|&f| f.todos) {
-        let path:&str = &shellexpand::tilde(&local_file.path);
-        let mut lines:Vec<String> = vec![];
-        let mut found = false;
-        let mut original_contents = get_file_contents(path)?;
-        for (num, line) in original_contents.lines().enumerate() {
-            match &mut Task::from_str(&line) {
-                Ok(task) => {
-                    if task.calc_hash() == hash {
-                        task.finished = finished;
-                        found = true;
-                        new_hash.push_str(&task.calc_hash());
-                        lines.push(format!("{}", task));
-                        continue;
-                    }
-                },
-                Err(_) => {
-                    eprintln!("ERROR parsing todo in {}:{}", path, num);
-                }
-            }
+/// Where changes made from the page are backed up and recorded for undoing.
+pub struct Storage {
+    pub backups: backups::Backups,
+    /// The path of the undo journal.
+    pub journal: String,
+}
 
-            lines.push(String::from(line));
+impl Storage {
+    /// The backup store from `backup_store`, and the journal next to it.
+    pub fn from_local_config() -> Storage {
+        Storage {
+            backups: backup_store(),
+            journal: shellexpand::tilde(JOURNAL_PATH).to_string(),
         }
+    }
 
-        if found {
-            {
-                // Write a backup
-                use std::hash::{Hash, Hasher};
-                let mut hasher = std::collections::hash_map::DefaultHasher::new();
-                original_contents.hash(&mut hasher);
-                let backup_dir = shellexpand::tilde("~/.homepage/backups/");
-                let mut backup_path = backup_dir.to_string();
-                std::fs::create_dir_all(&std::path::Path::new(&backup_dir.to_string())).context(format!("could not create backup path {}", backup_path))?;
-                backup_path.push_str(&hasher.finish().to_string());
-                let mut f = File::create(&backup_path).context(format!("could not create backup path {}", backup_path))?;
-                f.write_all(&original_contents.into_bytes())?;
-            }
+    /// Adds a change to the undo journal, if anything changed.
+    fn record(&self, operation: backups::Operation, changes: Vec<journal::FileChange>) {
+        if !changes.is_empty() {
+            self.record_entry(journal::Entry::new(operation, changes));
+        }
+    }
 
-            File::create(&path)
-                .expect(&format!("could not write back {}", path))
-                .write_all(&lines.join("\n").into_bytes())?;
+    /// Adds an entry to the undo journal. The change has already been made,
+    /// so failing to record it isn't worth failing the request over.
+    fn record_entry(&self, entry: journal::Entry) {
+        let operation = entry.operation;
+        if let Err(e) = journal::record(&self.journal, entry) {
+            eprintln!("could not record {} in the undo journal: {}", operation, e);
         }
+    }
+}
 
-        found_any = found_any || found;
+/// Reads a todo file we're about to change. Writing the file changes what
+/// the relative dates left in it mean, if they're read at all, so they're
+/// all resolved first, as of the day it was last changed. The resolved text
+/// stands in for what was read from then on: it's what gets backed up, and
+/// what undoing the change puts back.
+///
+/// Tasks should be found with `identity::locate` before this, since their
+/// versions are of the lines as they were read.
+fn pin_relative_dates(file: &mut LockedFile, relative_dates: bool) -> TodoFile {
+    let mut todo_file = TodoFile::parse(&file.contents);
+    if let (true, Some(modified)) = (relative_dates, file.state.as_ref().map(|s| s.modification_time)) {
+        let base = local_date(modified);
+        for index in 0..todo_file.lines.len() {
+            let line = edit::resolve_relative_dates(&todo_file.lines[index].text, &base);
+            todo_file.set_text(index, line);
+        }
+        file.contents = todo_file.to_string();
     }
+    todo_file
+}
 
-    if found_any {
-        Ok(new_hash)
-    } else {
-        Err(format_err!("no todo for hash {}", hash))
+/// Writes back a todo file that we've edited, keeping a backup of what was
+/// there before.
+fn write_todo_file(storage: &Storage, file: &mut LockedFile, todo_file: &TodoFile, operation: backups::Operation) -> Result<(), failure::Error> {
+    storage.backups.save(&file.path, &file.contents, operation)?;
+    let change = journal::FileChange::write(file, &todo_file.to_string())
+        .context(format!("could not write back {}", file.path))?;
+    storage.record(operation, vec![change]);
+
+    Ok(())
+}
+
+/// Undoes the last change made from the page.
+pub fn undo_last_change() -> Result<journal::Entry, failure::Error> {
+    let storage = Storage::from_local_config();
+    journal::undo(&storage.journal, &storage.backups)
+}
+
+/// Redoes the last change that was undone.
+pub fn redo_last_change() -> Result<journal::Entry, failure::Error> {
+    let storage = Storage::from_local_config();
+    journal::redo(&storage.journal, &storage.backups)
+}
+
+fn mark_todo_in_file(storage: &Storage, path: &str, auto_project: &str, relative_dates: bool, id: &TaskId, version: &str, finished: bool) -> Result<TodoUpdate, failure::Error> {
+    let mut file = LockedFile::open(path)?;
+    let index = identity::locate(&TodoFile::parse(&file.contents).texts(), id, version)?;
+    let mut todo_file = pin_relative_dates(&mut file, relative_dates);
+
+    let was_finished = todo::spans(&todo_file.lines[index].text).finished.is_some();
+    let unfinished = Task::from_str(&todo_file.lines[index].text).ok();
+    let line = edit::set_finished(&todo_file.lines[index].text, finished, &datetools::today());
+    let task = Task::from_str(&line)
+        .map_err(|_| format_err!("could not parse todo at {}", id))?;
+    todo_file.set_text(index, line);
+
+    let recurrence = match recurrence::Recurrence::for_task(&task) {
+        Some(ref rec) if finished && !was_finished => {
+            // the copy is made from the task as it was, whose priority
+            // hasn't been moved into a `pri:` tag yet
+            let next = rec.next_task(unfinished.as_ref().unwrap_or(&task), &datetools::today());
+            // a subtask's next instance goes right after it, with the same
+            // indentation, so that it stays with its siblings
+            let indent = todo::spans(&todo_file.lines[index].text).indent;
+            let line = format!("{}{}", &todo_file.lines[index].text[..indent], next);
+            let next_index = if indent > 0 {
+                let end = tree::subtree_end(&todo_file.texts(), index);
+                todo_file.insert_line(end, line.clone());
+                end
+            } else {
+                todo_file.push_line(line.clone());
+                todo_file.lines.len() - 1
+            };
+            Some(TaskWithContext::new(path, next_index, &line, next, auto_project))
+        },
+        _ => None,
+    };
+
+    let operation = if finished { backups::Operation::Complete } else { backups::Operation::Reopen };
+    write_todo_file(storage, &mut file, &todo_file, operation)?;
+
+    Ok(TodoUpdate {
+        id: TaskId::new(path, index, &task),
+        version: identity::content_version(&todo_file.lines[index].text),
+        recurrence,
+        parent: None,
+        dependents: vec![],
+    })
+}
+
+/// Ticks or unticks the box of the checklist item `id`.
+fn mark_markdown_todo_in_file(storage: &Storage, path: &str, relative_dates: bool, id: &TaskId, version: &str, finished: bool) -> Result<TodoUpdate, failure::Error> {
+    let mut file = LockedFile::open(path)?;
+    let index = identity::locate(&TodoFile::parse(&file.contents).texts(), id, version)?;
+    let mut todo_file = pin_relative_dates(&mut file, relative_dates);
+
+    let line = markdown::set_checked(&todo_file.lines[index].text, finished);
+    let task = match markdown::parse_line(&line, None) {
+        Some(Ok(task)) => task,
+        _ => return Err(format_err!("could not parse todo at {}", id)),
+    };
+    todo_file.set_text(index, line);
+
+    let operation = if finished { backups::Operation::Complete } else { backups::Operation::Reopen };
+    write_todo_file(storage, &mut file, &todo_file, operation)?;
+
+    Ok(TodoUpdate {
+        id: TaskId::new(path, index, &task),
+        version: identity::content_version(&todo_file.lines[index].text),
+        recurrence: None,
+        parent: None,
+        dependents: vec![],
+    })
+}
+
+/// Marks the task `id` finished or unfinished, as long as it still reads the
+/// same as it did at `version`.
+pub fn mark_todo_completed(id: &TaskId, version: &str, finished: bool) -> Result<TodoUpdate, failure::Error> {
+    let meta = HomepageMeta::from_local_config()?;
+
+    let local_file = match meta.local.iter().find(|f| f.todos && f.expanded_path() == id.file) {
+        Some(local_file) => local_file,
+        None => return Err(TodoError::NotFound { id: id.clone() }.into()),
+    };
+    let mut update = match local_file.format {
+        FileFormat::TodoTxt => mark_todo_in_file(&Storage::from_local_config(), &id.file, &local_file.auto_project, local_file.relative_dates, id, version, finished)?,
+        FileFormat::Markdown => mark_markdown_todo_in_file(&Storage::from_local_config(), &id.file, local_file.relative_dates, id, version, finished)?,
+    };
+
+    // the parent and the dependents can be in other files
+    if finished || !update.id.tag.is_empty() {
+        let mut all_todos = tree::attach_tagged_subtasks(parse_all_todo_files(&meta, &mut vec![])?);
+        if finished {
+            update.parent = tree::parent_to_offer(&all_todos, &update.id)
+                .map(|t| TaskWithContext { subtasks: vec![], ..t.clone() });
+        }
+        if !update.id.tag.is_empty() {
+            let open = deps::open_ids(&all_todos);
+            deps::mark_blocked(&mut all_todos, &open);
+            update.dependents = deps::dependents(&all_todos, &update.id.tag).into_iter()
+                .map(|t| TaskWithContext { subtasks: vec![], ..t.clone() })
+                .collect();
+        }
     }
+
+    Ok(update)
 }
 
-fn update_file_history(path: &str) -> Result<FileStateCache, failure::Error> {
-    // Create empty YAML file if it's not there.
-    let meta_path_str: String;
-    {
-        let mut meta_path = String::from(path);
-        meta_path.push_str(".meta.yaml");
-        meta_path_str = meta_path;
+/// Checks the text of a task typed into the page, and resolves any relative
+/// dates in it as of today.
+fn new_todo_line(text: &str) -> Result<(String, Task), failure::Error> {
+    let text = text.trim();
+    if text.is_empty() || text.contains('\n') {
+        return Err(TodoError::Invalid { reason: "a todo must be a single, non-empty line".into() }.into());
+    }
+
+    let line = edit::resolve_relative_dates(text, &datetools::today());
+    match todo::check(&line).and_then(|_| Task::from_str(&line)) {
+        Ok(task) => Ok((line, task)),
+        Err(e) => Err(TodoError::Invalid { reason: format!("column {}: {}", e.column, e.kind) }.into()),
     }
+}
+
+fn add_todo_to_file(storage: &Storage, path: &str, auto_project: &str, format: FileFormat, relative_dates: bool, text: &str) -> Result<TaskWithContext, failure::Error> {
+    let (line, task) = new_todo_line(text)?;
+    let line = match format {
+        FileFormat::TodoTxt => line,
+        FileFormat::Markdown => markdown::new_item(&line),
+    };
+    let mut file = LockedFile::open(path)?;
+    let mut todo_file = pin_relative_dates(&mut file, relative_dates);
+    todo_file.push_line(line.clone());
+    write_todo_file(storage, &mut file, &todo_file, backups::Operation::Add)?;
+
+    Ok(TaskWithContext::new(path, todo_file.lines.len() - 1, &line, task, auto_project))
+}
 
-    if !Path::new(&meta_path_str).exists() {
-        let empty_file_state_cache = FileStateCache { states: vec![] };
-        let serialized_bytes = serde_yaml::to_string(&empty_file_state_cache)
-            .expect("failed turning fresh FileStateCache to YAML").into_bytes();
-        File::create(&meta_path_str)
-            .expect(&format!("could not create .metadata.yaml file at {}", meta_path_str))
-            .write_all(&serialized_bytes)?;
+fn edit_todo_in_file(storage: &Storage, path: &str, auto_project: &str, format: FileFormat, relative_dates: bool, id: &TaskId, version: &str, text: &str) -> Result<TaskWithContext, failure::Error> {
+    let mut file = LockedFile::open(path)?;
+    let index = identity::locate(&TodoFile::parse(&file.contents).texts(), id, version)?;
+    let mut todo_file = pin_relative_dates(&mut file, relative_dates);
 
-        println!("created file at {}", &meta_path_str);
+    // keep the task where it is in the tree, and a checklist item's box
+    let old = &todo_file.lines[index].text;
+    let prefix = match (format, markdown::checkbox(old)) {
+        (FileFormat::Markdown, Some(checkbox)) => checkbox.text,
+        _ => todo::spans(old).indent,
+    };
+    let (line, mut task) = new_todo_line(text)?;
+    let line = format!("{}{}", &old[..prefix], line);
+    if format == FileFormat::Markdown {
+        task.finished = markdown::checkbox(&line).map(|c| c.checked(&line)).unwrap_or(false);
     }
+    todo_file.set_text(index, line.clone());
+    write_todo_file(storage, &mut file, &todo_file, backups::Operation::Edit)?;
 
-    // Now that it's there, parse it
-    let contents = &get_file_contents(&meta_path_str)?;
-    let mut history : FileStateCache = serde_yaml::from_str(contents)
-        .expect(&format!("YAML has invalid structure: '{}'", meta_path_str));
+    Ok(TaskWithContext::new(path, index, &line, task, auto_project))
+}
 
-    // Update it if the file has changed.
-    let md = std::fs::metadata(path)?;
-    let file_state = FileState::from(&md);
+/// Moves the file `name` out of the list of the directory at `source`, an
+/// expanded path, and into its done folder.
+pub fn complete_directory_item(source: &str, name: &str) -> Result<std::path::PathBuf, failure::Error> {
+    let meta = HomepageMeta::from_local_config()?;
+    let destination = match meta.local.iter().find(|f| f.is_directory() && f.expanded_path() == source) {
+        Some(local_file) => directory::complete(local_file, name)?,
+        None => return Err(directory::ItemNotFound { source: source.to_string(), name: name.to_string() }.into()),
+    };
+    let from = Path::new(source).join(name);
+    Storage::from_local_config().record_entry(journal::Entry::moved(backups::Operation::Complete,
+        &from.to_string_lossy(), &destination.to_string_lossy()));
+    Ok(destination)
+}
 
-    if history.states.is_empty() || history.states[history.states.len() - 1] != file_state {
-        history.states.push(file_state);
+/// Adds a task to the end of the todo file at `file`.
+pub fn add_todo(file: &str, text: &str) -> Result<TaskWithContext, failure::Error> {
+    let meta = HomepageMeta::from_local_config()?;
+    match meta.local.iter().find(|f| f.todos && f.expanded_path() == file) {
+        Some(local_file) => add_todo_to_file(&Storage::from_local_config(), file, &local_file.auto_project, local_file.format, local_file.relative_dates, text),
+        None => Err(TodoError::Invalid { reason: format!("{} isn't a configured todo file", file) }.into()),
+    }
+}
 
-        const MAX_ELEMS:usize = 200;
-        if history.states.len() > MAX_ELEMS {
-            let num_to_drain = history.states.len() - MAX_ELEMS;
-            history.states.drain(0..num_to_drain);
-        }
+/// Replaces the text of the task `id`, as long as it still reads the same as
+/// it did at `version`.
+pub fn edit_todo(id: &TaskId, version: &str, text: &str) -> Result<TaskWithContext, failure::Error> {
+    let meta = HomepageMeta::from_local_config()?;
+    match meta.local.iter().find(|f| f.todos && f.expanded_path() == id.file) {
+        Some(local_file) => edit_todo_in_file(&Storage::from_local_config(), &id.file, &local_file.auto_project, local_file.format, local_file.relative_dates, id, version, text),
+        None => Err(TodoError::NotFound { id: id.clone() }.into()),
+    }
+}
 
-        let new_yaml = serde_yaml::to_string(&history)
-            .expect("could not convert FileStateCache to YAML").into_bytes();
+/// The result of starting or stopping a timer.
+#[derive(Serialize, Debug, Clone)]
+pub struct TimerUpdate {
+    pub id: TaskId,
+    pub version: String,
+    /// The task's `spent:` value, if it has one.
+    pub spent: String,
+}
+
+/// An `id:` tag for a task that needs one.
+fn new_id_tag(path: &str, index: usize) -> String {
+    let version = identity::content_version(&format!("{}:{}:{:?}", path, index, SystemTime::now()));
+    format!("t{}", &version[..6.min(version.len())])
+}
 
-        File::create(&meta_path_str)?.write_all(&new_yaml)
-            .expect(&format!("could not write to {}", meta_path_str));
+fn start_timer_in_file(storage: &Storage, path: &str, relative_dates: bool, timers: &mut timetrack::Timers, id: &TaskId, version: &str) -> Result<TimerUpdate, failure::Error> {
+    if timers.running_for(id, version).is_some() {
+        return Err(TodoError::Invalid { reason: format!("a timer is already running for {}", id) }.into());
     }
 
-    Ok(history)
+    let mut file = LockedFile::open(path)?;
+    let index = identity::locate(&TodoFile::parse(&file.contents).texts(), id, version)?;
+    let mut todo_file = pin_relative_dates(&mut file, relative_dates);
+    let mut task = Task::from_str(&todo_file.lines[index].text)
+        .map_err(|_| format_err!("could not parse todo at {}", id))?;
+
+    // the timer finds the task by its tag, so that changing the task while
+    // the timer runs doesn't lose it
+    if !task.tags.contains_key("id") {
+        let tag = new_id_tag(path, index);
+        let line = edit::set_tag(&todo_file.lines[index].text, "id", &tag);
+        todo_file.set_text(index, line);
+        task.tags.insert("id".to_string(), tag);
+        write_todo_file(storage, &mut file, &todo_file, backups::Operation::StartTimer)?;
+    }
+
+    let update = TimerUpdate {
+        id: TaskId::new(path, index, &task),
+        version: identity::content_version(&todo_file.lines[index].text),
+        spent: task.tags.get("spent").cloned().unwrap_or_default(),
+    };
+    timers.running.push(timetrack::RunningTimer {
+        id: update.id.clone(),
+        version: update.version.clone(),
+        started: SystemTime::now(),
+    });
+    Ok(update)
 }
 
-fn get_deadlines() -> Result<Deadlines, failure::Error> {
-    let deadlines_path = String::from(shellexpand::tilde(DEADLINES_JSON_PATH));
+/// Starts a timer for the task `id`.
+pub fn start_timer(id: &TaskId, version: &str) -> Result<TimerUpdate, failure::Error> {
+    let meta = HomepageMeta::from_local_config()?;
+    let local_file = match meta.local.iter().find(|f| f.todos && f.expanded_path() == id.file) {
+        Some(local_file) => local_file,
+        None => return Err(TodoError::NotFound { id: id.clone() }.into()),
+    };
+
+    // the timers are locked before the todo file, as they are when stopping
+    let mut timers_file = LockedFile::open(&shellexpand::tilde(TIMERS_PATH))?;
+    let mut timers = timetrack::Timers::read(&timers_file)?;
+    let update = start_timer_in_file(&Storage::from_local_config(), &id.file, local_file.relative_dates, &mut timers, id, version)?;
+    timers.save(&mut timers_file)?;
+    Ok(update)
+}
 
-    if !Path::new(&deadlines_path).exists() {
+/// Throws away the timer for the task `id` without recording any time, for
+/// a timer whose task can't be found anymore.
+pub fn discard_timer(id: &TaskId, version: &str) -> Result<(), failure::Error> {
+    let mut timers_file = LockedFile::open(&shellexpand::tilde(TIMERS_PATH))?;
+    let mut timers = timetrack::Timers::read(&timers_file)?;
+    match timers.running_for(id, version) {
+        Some(index) => timers.running.remove(index),
+        None => return Err(TodoError::Invalid { reason: format!("no timer is running for {}", id) }.into()),
+    };
+    timers.save(&mut timers_file)
+}
+
+fn stop_timer_in_file(storage: &Storage, path: &str, auto_project: &str, relative_dates: bool, timers: &mut timetrack::Timers, id: &TaskId, version: &str) -> Result<TimerUpdate, failure::Error> {
+    let timer = match timers.running_for(id, version) {
+        Some(index) => timers.running.remove(index),
+        None => return Err(TodoError::Invalid { reason: format!("no timer is running for {}", id) }.into()),
+    };
+    let elapsed = timer.started.elapsed().unwrap_or_default();
+
+    let mut file = LockedFile::open(path)?;
+    let index = identity::locate(&TodoFile::parse(&file.contents).texts(), id, version)?;
+    let mut todo_file = pin_relative_dates(&mut file, relative_dates);
+    let task = Task::from_str(&todo_file.lines[index].text)
+        .map_err(|_| format_err!("could not parse todo at {}", id))?;
+
+    let spent = datetools::format_duration(&(task.spent().unwrap_or_default() + elapsed));
+    let line = edit::set_tag(&todo_file.lines[index].text, "spent", &spent);
+    todo_file.set_text(index, line);
+    write_todo_file(storage, &mut file, &todo_file, backups::Operation::StopTimer)?;
+
+    let todo = TaskWithContext::new(path, index, &todo_file.lines[index].text, task, auto_project);
+    timers.sessions.push(timetrack::Session {
+        file: path.to_string(),
+        projects: timetrack::projects(&todo),
+        subject: todo.task.subject.clone(),
+        ended: SystemTime::now(),
+        seconds: elapsed.as_secs(),
+    });
+
+    Ok(TimerUpdate { id: todo.id, version: todo.version, spent })
+}
+
+/// Stops the timer for the task `id`, adding the time to its `spent:` tag.
+pub fn stop_timer(id: &TaskId, version: &str) -> Result<TimerUpdate, failure::Error> {
+    let meta = HomepageMeta::from_local_config()?;
+    let local_file = match meta.local.iter().find(|f| f.todos && f.expanded_path() == id.file) {
+        Some(local_file) => local_file,
+        None => return Err(TodoError::NotFound { id: id.clone() }.into()),
+    };
+
+    let mut timers_file = LockedFile::open(&shellexpand::tilde(TIMERS_PATH))?;
+    let mut timers = timetrack::Timers::read(&timers_file)?;
+    let update = stop_timer_in_file(&Storage::from_local_config(), &id.file, &local_file.auto_project, local_file.relative_dates, &mut timers, id, version)?;
+    timers.save(&mut timers_file)?;
+    Ok(update)
+}
+
+/// Reads the tasks in every configured todo file, for working out which tasks
+/// are blocked. Files that aren't there are left out, as they are on the page.
+fn parse_all_todo_files(meta: &HomepageMeta, diagnostics: &mut Vec<Diagnostic>) -> Result<Vec<TaskWithContext>, failure::Error> {
+    let mut all_todos = vec![];
+    for local_file in meta.local.iter().filter(|f| f.todos && Path::new(&f.expanded_path()).exists()) {
+        all_todos.extend(read_tasks(local_file, diagnostics)?);
+    }
+    Ok(all_todos)
+}
+
+/// Reads the deadlines saved by `update_deadlines`, if there are any.
+fn read_deadlines(path: &str) -> Result<Deadlines, failure::Error> {
+    if !Path::new(path).exists() {
         return Ok(Deadlines::new())
     }
 
-    let mut deadlines:Deadlines = serde_yaml::from_str(&get_file_contents(&deadlines_path)?).expect(
-        &format!("Couldn't parse JSON at {}", DEADLINES_JSON_PATH));
+    Ok(serde_yaml::from_str(&get_file_contents(path)?)
+        .map_err(|e| format_err!("Couldn't parse JSON at {}: {}", path, e))?)
+}
 
+/// The deadlines that are today or later.
+fn upcoming_deadlines(deadlines: &Deadlines) -> Deadlines {
     use datetools::DateWhen;
+    let mut deadlines = deadlines.clone();
     deadlines.deadlines.retain(
-        |d| match DateWhen::for_date(&d.start.to_naive_date().unwrap()) {
+        |d| match d.start.to_date_or_time().unwrap().date_when() {
             DateWhen::Future => true,
             DateWhen::Today => true,
             DateWhen::Past => false,
         });
-    Ok(deadlines)
+    deadlines
 }
 
+/// The data for the page. Files are only read again once they've changed;
+/// see `store`.
 pub fn update_data(files_to_include: &Vec<String>) -> Result<CachedData, failure::Error> {
-    let mut todos_count:usize = 0;
-    let mut all_todos:Vec<TaskWithContext> = vec![];
-    let mut files:Vec<LocalFileDescWithState> = vec![];
-
-    for local_file in &HomepageMeta::from_local_config()?.local {
-        let path = local_file.expanded_path();
-        let history = update_file_history(&path)?;
+    store::data(files_to_include)
+}
 
-        let file_is_showing_todos = local_file.todos && if local_file.hide_in_index {
-            files_to_include.contains(&local_file.name)
-        } else {
-            files_to_include.is_empty() || files_to_include.contains(&local_file.name)
-        };
 
-        if file_is_showing_todos {
-            let todos = parse_todo_file(&path)?;
-            todos_count += todos.iter().filter(|c| !c.finished && c.priority == 0).count();
-            for todo in &todos {
-                all_todos.push(TaskWithContext {
-                    task: todo.clone(),
-                    auto_project: local_file.auto_project.clone(),
-                });
-            }
+impl TaskWithContext {
+    /// The task read from `line`, the line at `index` in the file at `path`,
+    /// before it's been put in a tree or checked against the others.
+    pub fn new(path: &str, index: usize, line: &str, task: Task, auto_project: &str) -> TaskWithContext {
+        TaskWithContext {
+            id: TaskId::new(path, index, &task),
+            version: identity::content_version(line),
+            task,
+            auto_project: auto_project.to_string(),
+            subtasks: vec![],
+            blocked_by: vec![],
+            timer_running: false,
+            warnings: vec![],
         }
-
-        let update_state = if local_file.frequency_goal_seconds > 0 {
-            assert!(!history.states.is_empty());
-            {
-                let last_state = &history.states[history.states.len() - 1];
-                let diff = SystemTime::now().duration_since(last_state.modification_time).unwrap();
-                let seconds = time::Duration::from_std(diff).unwrap().num_seconds();
-                if seconds > local_file.frequency_goal_seconds {
-                    UpdateState::NeedsUpdate
-                } else {
-                    UpdateState::Ok
-                }
-            }
-        } else {
-            UpdateState::NoGoal
-        };
-
-        files.push(LocalFileDescWithState {
-            desc: local_file.clone(),
-            states: history.states,
-            update_state,
-            file_is_showing_todos,
-        });
     }
 
-    Ok(CachedData {
-        last_update: SystemTime::now(),
-        todos_count,
-        todos: all_todos,
-        local_files: files,
-        deadlines: get_deadlines()?,
-    })
-}
-
-
-impl TaskWithContext {
     pub fn should_show_auto_project(&self) -> bool {
         !self.auto_project.is_empty() && !self.task.projects.contains(&self.auto_project)
     }
@@ -508,6 +1052,23 @@ impl TaskWithContext {
             self.task.subject.clone()
         }
     }
+
+    /// This task followed by all of its subtasks, depth first.
+    pub fn with_subtasks(&self) -> Vec<&TaskWithContext> {
+        let mut all = vec![self];
+        for subtask in &self.subtasks {
+            all.extend(subtask.with_subtasks());
+        }
+        all
+    }
+
+    pub fn finished_subtasks_count(&self) -> usize {
+        self.subtasks.iter().filter(|t| t.task.finished).count()
+    }
+
+    pub fn warnings_text(&self) -> String {
+        self.warnings.iter().map(|w| w.reason.as_str()).collect::<Vec<_>>().join("; ")
+    }
 }
 
 pub fn get_done_filename(todo_filename: &str) -> Option<String> {
@@ -532,6 +1093,17 @@ pub fn get_done_filename(todo_filename: &str) -> Option<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Write;
+    use backups::{Backups, Retention};
+
+    /// Backups and a journal in `dir`, so that tests leave the real ones alone.
+    fn storage_in(dir: &tempfile::TempDir) -> Storage {
+        Storage {
+            backups: Backups::new(dir.path().join("backups").to_str().unwrap(), Retention::default()),
+            journal: dir.path().join("journal.yaml").to_str().unwrap().to_string(),
+        }
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
@@ -577,15 +1149,290 @@ x another thing that is done
 a todo
 x 2015-05-01 a third thing that is done
 ").unwrap();
-        let count = archive_tasks_in_todo_file(&path_as_str).expect("error while archiving");
+        let count = archive_tasks_in_todo_file(&storage_in(&dir), &path_as_str, false, &HashSet::new(), &mut vec![]).expect("error while archiving");
         assert_eq!(count, 3, "expected 3 archived done todos");
 
-        let archived = parse_todo_file(&get_done_filename(&path_as_str).unwrap()).unwrap();
+        let archived = parse_todo_file(&get_done_filename(&path_as_str).unwrap(), "", None, &mut vec![]).unwrap();
         assert_eq!(archived.len(), 3, "expected 3 archived done todos in the done.txt file");
-        assert_eq!(archived[2].subject, "a third thing that is done");
+        assert_eq!(archived[2].task.subject, "a third thing that is done");
 
-        let todos = parse_todo_file(&path_as_str).unwrap();
+        let todos = parse_todo_file(&path_as_str, "", None, &mut vec![]).unwrap();
         assert_eq!(todos.len(), 2, "expected 2 remaining todos");
-        assert_eq!(todos[1].subject, "a todo");
+        assert_eq!(todos[1].task.subject, "a todo");
+    }
+
+    #[test]
+    fn test_archive_records_files_archived_before_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let todo_path = dir.path().join("todo.txt");
+        File::create(&todo_path).unwrap().write_all(b"x call bob\nbuy milk\n").unwrap();
+        // a directory can't be read as a done.txt
+        let broken_path = dir.path().join("broken").join("todo.txt");
+        fs::create_dir_all(dir.path().join("broken").join("done.txt")).unwrap();
+        File::create(&broken_path).unwrap().write_all(b"x call alice\n").unwrap();
+        let local_files:Vec<LocalFileDesc> = [&todo_path, &broken_path].iter()
+            .map(|path| serde_yaml::from_str(&format!("path: {}\ntodos: true", path.display())).unwrap())
+            .collect();
+
+        let storage = storage_in(&dir);
+        assert!(archive_files(&storage, &local_files).is_err());
+        assert_eq!(get_file_contents(todo_path.to_str().unwrap()).unwrap(), "buy milk\n");
+
+        journal::undo(&storage.journal, &storage.backups).unwrap();
+        assert_eq!(get_file_contents(todo_path.to_str().unwrap()).unwrap(), "x call bob\nbuy milk\n");
+    }
+
+    #[test]
+    fn test_parse_diagnostics() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("todo.txt");
+        let path_as_str = file_path.clone().into_os_string().into_string().unwrap();
+        File::create(&file_path).unwrap().write_all(b"fine\n\n(A) also fine due:2018-13-40\n").unwrap();
+
+        let mut diagnostics = vec![];
+        let todos = parse_todo_file(&path_as_str, "", None, &mut diagnostics).unwrap();
+        assert_eq!(diagnostics, vec![Diagnostic {
+            file: path_as_str.clone(),
+            line: 3,
+            column: 19,
+            reason: "invalid date '2018-13-40'".into(),
+        }]);
+        // the line is still shown, with what's wrong with it
+        assert_eq!(todos.len(), 2);
+        assert_eq!(todos[1].task.priority, 0);
+        assert_eq!(todos[1].warnings, diagnostics);
+        assert!(todos[0].warnings.is_empty());
+    }
+
+    #[test]
+    fn test_complete_recurring_todo() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("todo.txt");
+        let path_as_str = file_path.clone().into_os_string().into_string().unwrap();
+        let mut file = File::create(file_path).unwrap();
+        write!(file, "take out the trash due:2018-05-01 rec:+1w\nsomething else").unwrap();
+
+        let todo = parse_todo_file(&path_as_str, "chores", None, &mut vec![]).unwrap().remove(0);
+        let update = mark_todo_in_file(&storage_in(&dir), &path_as_str, "chores", false, &todo.id, &todo.version, true)
+            .expect("expected to complete the todo");
+
+        let next = update.recurrence.expect("expected a new recurring todo");
+        assert_eq!(next.auto_project, "chores");
+        assert_eq!(next.task.due_date, Some(Date::from_ymd(2018, 5, 8)));
+
+        let todos = parse_todo_file(&path_as_str, "chores", None, &mut vec![]).unwrap();
+        assert_eq!(todos.len(), 3);
+        assert!(todos[0].task.finished);
+        assert_eq!(todos[0].version, update.version);
+        assert_eq!(todos[2], next);
+    }
+
+    #[test]
+    fn test_complete_recurring_todo_keeps_priority() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("todo.txt");
+        let path_as_str = file_path.clone().into_os_string().into_string().unwrap();
+        File::create(&file_path).unwrap().write_all(b"(A) foo rec:1w\n").unwrap();
+
+        let todo = parse_todo_file(&path_as_str, "", None, &mut vec![]).unwrap().remove(0);
+        mark_todo_in_file(&storage_in(&dir), &path_as_str, "", false, &todo.id, &todo.version, true).unwrap();
+
+        let contents = get_file_contents(&path_as_str).unwrap();
+        let lines:Vec<&str> = contents.lines().collect();
+        assert!(lines[0].ends_with("pri:A"), "{}", lines[0]);
+        assert!(lines[1].starts_with("(A) "), "{}", lines[1]);
+        assert!(!lines[1].contains("pri:"), "{}", lines[1]);
+    }
+
+    #[test]
+    fn test_mark_todo_preserves_formatting() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("todo.txt");
+        let path_as_str = file_path.clone().into_os_string().into_string().unwrap();
+        let contents = "\u{feff}(A) call  bob t:2018-01-01 due:2018-05-01 @phone\r\nsomething else\r\n";
+        File::create(&file_path).unwrap().write_all(contents.as_bytes()).unwrap();
+
+        let todo = parse_todo_file(&path_as_str, "", None, &mut vec![]).unwrap().remove(0);
+        mark_todo_in_file(&storage_in(&dir), &path_as_str, "", false, &todo.id, &todo.version, true).unwrap();
+        assert_eq!(get_file_contents(&path_as_str).unwrap(), format!(
+            "{}x {} call  bob t:2018-01-01 due:2018-05-01 @phone pri:A\r\nsomething else\r\n",
+            "\u{feff}", datetools::today().format("%Y-%m-%d")));
+
+        archive_tasks_in_todo_file(&storage_in(&dir), &path_as_str, false, &HashSet::new(), &mut vec![]).unwrap();
+        assert_eq!(get_file_contents(&path_as_str).unwrap(), "\u{feff}something else\r\n");
+    }
+
+    #[test]
+    fn test_mark_todo_moved_or_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("todo.txt");
+        let path_as_str = file_path.clone().into_os_string().into_string().unwrap();
+        File::create(&file_path).unwrap().write_all(b"buy milk\ncall bob").unwrap();
+
+        let todos = parse_todo_file(&path_as_str, "", None, &mut vec![]).unwrap();
+
+        // someone adds a line at the top in another editor
+        File::create(&file_path).unwrap().write_all(b"new thing\nbuy milk\ncall bob").unwrap();
+        let update = mark_todo_in_file(&storage_in(&dir), &path_as_str, "", false, &todos[1].id, &todos[1].version, true).unwrap();
+        assert_eq!(update.id.line, 2);
+        assert!(parse_todo_file(&path_as_str, "", None, &mut vec![]).unwrap()[2].task.finished);
+
+        // ...and then edits the other one
+        File::create(&file_path).unwrap().write_all(b"new thing\nbuy oat milk\nx call bob").unwrap();
+        let err = mark_todo_in_file(&storage_in(&dir), &path_as_str, "", false, &todos[0].id, &todos[0].version, true).unwrap_err();
+        match err.downcast::<TodoError>() {
+            Ok(TodoError::Conflict { .. }) => {},
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_add_and_edit_todos() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("todo.txt");
+        let path_as_str = file_path.clone().into_os_string().into_string().unwrap();
+        File::create(&file_path).unwrap().write_all(b"plan trip\r\n  book flights\r\n").unwrap();
+
+        let tomorrow = datetools::today().succ();
+        let added = add_todo_to_file(&storage_in(&dir), &path_as_str, "", FileFormat::TodoTxt, false, "pack due:tomorrow").unwrap();
+        assert_eq!(added.id.line, 2);
+        assert_eq!(added.task.due_date, Some(tomorrow));
+
+        let todos = parse_todo_file(&path_as_str, "", None, &mut vec![]).unwrap();
+        let flights = &todos[0].subtasks[0];
+        let edited = edit_todo_in_file(&storage_in(&dir), &path_as_str, "", FileFormat::TodoTxt, false, &flights.id, &flights.version, "book flights t:today").unwrap();
+        assert_eq!(edited.task.threshold_date, Some(datetools::today()));
+
+        assert_eq!(get_file_contents(&path_as_str).unwrap(), format!(
+            "plan trip\r\n  book flights t:{}\r\npack due:{}\r\n",
+            datetools::today().format("%Y-%m-%d"), tomorrow.format("%Y-%m-%d")));
+
+        assert!(add_todo_to_file(&storage_in(&dir), &path_as_str, "", FileFormat::TodoTxt, false, "pack due:someday").is_err());
+    }
+
+    #[test]
+    fn test_read_relative_dates() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("todo.txt");
+        let path_as_str = file_path.clone().into_os_string().into_string().unwrap();
+        File::create(&file_path).unwrap().write_all(b"call bob due:fri\n").unwrap();
+
+        let mut diagnostics = vec![];
+        let todos = parse_todo_file(&path_as_str, "", None, &mut diagnostics).unwrap();
+        assert_eq!(todos[0].task.due_date, None);
+        assert_eq!(diagnostics.len(), 1);
+
+        let base = Date::from_ymd(2018, 5, 2);
+        let todos = parse_todo_file(&path_as_str, "", Some(base), &mut vec![]).unwrap();
+        assert_eq!(todos[0].task.due_date, Some(Date::from_ymd(2018, 5, 4)));
+
+        // changing the file would change what they mean, so they're all
+        // pinned down first
+        File::create(&file_path).unwrap().write_all(b"call bob due:fri\nwater plants t:+3d\n").unwrap();
+        let today = datetools::today();
+        add_todo_to_file(&storage_in(&dir), &path_as_str, "", FileFormat::TodoTxt, true, "pack").unwrap();
+        assert_eq!(get_file_contents(&path_as_str).unwrap(), format!("{}\n{}\npack\n",
+            edit::resolve_relative_dates("call bob due:fri", &today),
+            edit::resolve_relative_dates("water plants t:+3d", &today)));
+    }
+
+    #[test]
+    fn test_stop_timer() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("todo.txt");
+        let path_as_str = file_path.clone().into_os_string().into_string().unwrap();
+        File::create(&file_path).unwrap().write_all(b"+api write endpoint est:3h spent:1h\n").unwrap();
+
+        let todo = parse_todo_file(&path_as_str, "work", None, &mut vec![]).unwrap().remove(0);
+        let mut timers = timetrack::Timers::default();
+        timers.running.push(timetrack::RunningTimer {
+            id: todo.id.clone(),
+            version: todo.version.clone(),
+            started: SystemTime::now() - std::time::Duration::from_secs(90 * 60),
+        });
+
+        let update = stop_timer_in_file(&storage_in(&dir), &path_as_str, "work", false, &mut timers, &todo.id, &todo.version).unwrap();
+        assert_eq!(update.spent, "2h30m");
+        assert_eq!(get_file_contents(&path_as_str).unwrap(), "+api write endpoint est:3h spent:2h30m\n");
+        assert!(timers.running.is_empty());
+        assert_eq!(timers.sessions[0].projects, vec!["api".to_string(), "work".to_string()]);
+        assert_eq!(timers.sessions[0].seconds, 90 * 60);
+
+        assert!(stop_timer_in_file(&storage_in(&dir), &path_as_str, "work", false, &mut timers, &update.id, &update.version).is_err());
+    }
+
+    #[test]
+    fn test_start_timer_tags_task() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("todo.txt");
+        let path_as_str = file_path.clone().into_os_string().into_string().unwrap();
+        File::create(&file_path).unwrap().write_all(b"write endpoint\n").unwrap();
+
+        let todo = parse_todo_file(&path_as_str, "work", None, &mut vec![]).unwrap().remove(0);
+        let mut timers = timetrack::Timers::default();
+        let started = start_timer_in_file(&storage_in(&dir), &path_as_str, false, &mut timers, &todo.id, &todo.version).unwrap();
+        assert!(!started.id.tag.is_empty());
+        assert_eq!(get_file_contents(&path_as_str).unwrap(), format!("write endpoint id:{}\n", started.id.tag));
+        assert!(start_timer_in_file(&storage_in(&dir), &path_as_str, false, &mut timers, &started.id, &started.version).is_err());
+
+        // the task can be changed while the timer runs
+        edit_todo_in_file(&storage_in(&dir), &path_as_str, "work", FileFormat::TodoTxt, false, &started.id, &started.version,
+                          &format!("write endpoint est:1h id:{}", started.id.tag)).unwrap();
+        let todo = parse_todo_file(&path_as_str, "work", None, &mut vec![]).unwrap().remove(0);
+        assert!(timers.running_for(&todo.id, &todo.version).is_some());
+    }
+
+    #[test]
+    fn test_subtasks() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("todo.txt");
+        let path_as_str = file_path.clone().into_os_string().into_string().unwrap();
+        File::create(&file_path).unwrap().write_all(
+            b"plan trip\n  book flights\n  water plants rec:1w\nunrelated\n").unwrap();
+
+        let todos = parse_todo_file(&path_as_str, "", None, &mut vec![]).unwrap();
+        assert_eq!(todos.len(), 2);
+        assert_eq!(todos[0].subtasks.len(), 2);
+
+        let parent_to_offer = |id: &TaskId| {
+            let todos = tree::attach_tagged_subtasks(parse_todo_file(&path_as_str, "", None, &mut vec![]).unwrap());
+            tree::parent_to_offer(&todos, id).map(|t| t.id.line)
+        };
+
+        let flights = &todos[0].subtasks[0];
+        let update = mark_todo_in_file(&storage_in(&dir), &path_as_str, "", false, &flights.id, &flights.version, true).unwrap();
+        assert_eq!(parent_to_offer(&update.id), None);
+
+        // the next instance of a recurring subtask stays with its siblings
+        let plants = &todos[0].subtasks[1];
+        let update = mark_todo_in_file(&storage_in(&dir), &path_as_str, "", false, &plants.id, &plants.version, true).unwrap();
+        assert_eq!(update.recurrence.unwrap().id.line, 3);
+        assert_eq!(parent_to_offer(&update.id), None);
+
+        let todos = parse_todo_file(&path_as_str, "", None, &mut vec![]).unwrap();
+        assert_eq!(todos[0].subtasks.len(), 3);
+        assert_eq!(todos[0].finished_subtasks_count(), 2);
+
+        // an unfinished subtask keeps its finished parent out of done.txt
+        File::create(&file_path).unwrap().write_all(
+            b"x plan trip\n  x book flights\n  pack\nx done\n").unwrap();
+        assert_eq!(archive_tasks_in_todo_file(&storage_in(&dir), &path_as_str, false, &HashSet::new(), &mut vec![]).unwrap(), 2);
+        let todos = parse_todo_file(&path_as_str, "", None, &mut vec![]).unwrap();
+        assert_eq!(todos.len(), 1);
+        assert_eq!(todos[0].subtasks.len(), 1);
+
+        let pack = &todos[0].subtasks[0];
+        let update = mark_todo_in_file(&storage_in(&dir), &path_as_str, "", false, &pack.id, &pack.version, true).unwrap();
+        assert_eq!(parent_to_offer(&update.id), None, "the parent is already finished");
+
+        // and so does one in another file
+        let errands_path = dir.path().join("errands").join("todo.txt");
+        fs::create_dir(dir.path().join("errands")).unwrap();
+        File::create(&file_path).unwrap().write_all(b"x plan trip id:trip\n").unwrap();
+        File::create(&errands_path).unwrap().write_all(b"buy sunscreen parent:trip\n").unwrap();
+        let local_files:Vec<LocalFileDesc> = [&file_path, &errands_path].iter()
+            .map(|path| serde_yaml::from_str(&format!("path: {}\ntodos: true", path.display())).unwrap())
+            .collect();
+        assert_eq!(archive_files(&storage_in(&dir), &local_files).unwrap(), 0);
     }
 }
@@ -0,0 +1,227 @@
+//! Markdown files with GitHub-style checklists, as todo files.
+//!
+//! With `format: markdown`, every list item that starts with a checkbox is a
+//! task, finished if the box is ticked:
+//!
+//! ```markdown
+//! ## Next release
+//!
+//! - [ ] write the changelog due:2018-09-01 +docs
+//!   - [x] collect the merged PRs
+//! ```
+//!
+//! The text after the box is read like a todo.txt line, so tags, contexts
+//! and projects work as usual, and items nest by indentation. The heading an
+//! item is under is its project, like the file's `auto_project` (which items
+//! before any heading get), with spaces turned into dashes. Lines in fenced
+//! code blocks are left alone.
+//!
+//! Finishing an item ticks its box and changes nothing else on the line.
+
+use std::ops::Range;
+use std::str::FromStr;
+
+use regex::Regex;
+
+use diagnostics::Diagnostic;
+use edit::{self, TodoFile};
+use todo::Task;
+use {tree, Date, TaskWithContext};
+
+/// Where the parts of a checklist item are in its line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkbox {
+    /// The leading whitespace, which nests items.
+    pub indent: usize,
+    /// The character between the brackets.
+    pub mark: Range<usize>,
+    /// Where the item's text begins.
+    pub text: usize,
+}
+
+impl Checkbox {
+    pub fn checked(&self, line: &str) -> bool {
+        &line[self.mark.clone()] != " "
+    }
+}
+
+pub fn checkbox(line: &str) -> Option<Checkbox> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^([ \t]*)(?:[-*+]|\d+[.)])[ \t]+\[([ xX])\](?:[ \t]+|$)").unwrap();
+    }
+    let captures = RE.captures(line)?;
+    let mark = captures.get(2)?;
+    Some(Checkbox {
+        indent: captures.get(1)?.end(),
+        mark: mark.start()..mark.end(),
+        text: captures.get(0)?.end(),
+    })
+}
+
+/// The text of a heading line, if it is one.
+fn heading(line: &str) -> Option<&str> {
+    let rest = line.trim_start_matches('#');
+    let level = line.len() - rest.len();
+    if level == 0 || level > 6 || !(rest.is_empty() || rest.starts_with(' ')) {
+        return None;
+    }
+    Some(rest.trim().trim_end_matches('#').trim())
+}
+
+/// A heading as a project name, which can't have spaces in it.
+fn project_name(heading: &str) -> String {
+    heading.split_whitespace().collect::<Vec<_>>().join("-")
+}
+
+/// The task in a checklist line, if it is one.
+pub fn parse_line(line: &str, relative_to: Option<Date>) -> Option<Result<Task, ::todo::ParseError>> {
+    let checkbox = checkbox(line)?;
+    let text = &line[checkbox.text..];
+    let resolved = relative_to.map(|base| edit::resolve_relative_dates(text, &base));
+    Some(Task::from_str(resolved.as_ref().map(|s| s.as_str()).unwrap_or(text)).map(|mut task| {
+        task.finished = checkbox.checked(line);
+        task
+    }))
+}
+
+/// Reads the tasks in the checklists in `contents`, the file at `path`.
+pub fn parse(path: &str, contents: &str, auto_project: &str, relative_to: Option<Date>, diagnostics: &mut Vec<Diagnostic>) -> Vec<TaskWithContext> {
+    let mut tasks = vec![];
+    let mut project = auto_project.to_string();
+    let file = TodoFile::parse(contents);
+    let lines = file.texts();
+    let in_code = code_lines(&lines);
+    for (num, &line) in lines.iter().enumerate() {
+        if in_code[num] {
+            continue;
+        }
+        if let Some(heading) = heading(line) {
+            project = project_name(heading);
+            continue;
+        }
+        let checkbox = match checkbox(line) {
+            Some(checkbox) => checkbox,
+            None => continue,
+        };
+        let text = &line[checkbox.text..];
+        let resolved = relative_to.map(|base| edit::resolve_relative_dates(text, &base));
+        let text = resolved.as_ref().map(|s| s.as_str()).unwrap_or(text);
+        if let Some(mut todo) = ::read_task_line(path, num, line, text, checkbox.text, &project, diagnostics) {
+            todo.task.finished = checkbox.checked(line);
+            tasks.push((checkbox.indent, todo));
+        }
+    }
+    tree::nest_by_indent(tasks)
+}
+
+/// Ticks or unticks the box of a checklist item.
+pub fn set_checked(line: &str, checked: bool) -> String {
+    let mut text = line.to_string();
+    if let Some(checkbox) = checkbox(line) {
+        if checkbox.checked(line) != checked {
+            text.replace_range(checkbox.mark, if checked { "x" } else { " " });
+        }
+    }
+    text
+}
+
+/// A new checklist item.
+pub fn new_item(text: &str) -> String {
+    format!("- [ ] {}", text)
+}
+
+/// Whether each of `lines` is in a fenced code block, fences included.
+fn code_lines(lines: &[&str]) -> Vec<bool> {
+    let mut in_code = false;
+    lines.iter().map(|line| {
+        let fence = line.trim_start().starts_with("```");
+        if fence {
+            in_code = !in_code;
+        }
+        in_code || fence
+    }).collect()
+}
+
+/// Removes ticked items whose nested items are all ticked too, and returns
+/// how many lines went.
+pub fn remove_finished(file: &mut TodoFile) -> usize {
+    let is_checked = |line: &str| checkbox(line).map(|c| c.checked(line));
+    let mut removed = 0;
+    let mut index = 0;
+    while index < file.lines.len() {
+        let in_code = code_lines(&file.texts())[index];
+        let end = match is_checked(&file.lines[index].text) {
+            Some(true) if !in_code => tree::subtree_end(&file.texts(), index),
+            _ => {
+                index += 1;
+                continue;
+            },
+        };
+        let finished = file.lines[index..end].iter().all(|l| is_checked(&l.text) != Some(false));
+        if !finished {
+            index += 1;
+            continue;
+        }
+        for j in (index..end).rev() {
+            file.remove_line(j);
+            removed += 1;
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTES: &str = "# Notes
+
+- [ ] loose end
+* plain list item
+
+## Next release
+
+1. [x] tag it +git
+- [ ] write the changelog due:2018-09-01 @desk
+  - [x] collect the merged PRs
+  - [ ] ask about the docs
+```
+- [ ] not a task
+```
+- [X] announce it
+";
+
+    #[test]
+    fn read_and_tick_checklists() {
+        let todos = parse("notes.md", NOTES, "home", None, &mut vec![]);
+        let subjects:Vec<&str> = todos.iter().map(|t| t.task.subject.as_str()).collect();
+        assert_eq!(subjects, vec!["loose end", "tag it +git", "write the changelog @desk", "announce it"]);
+        assert_eq!(todos[0].auto_project, "Notes");
+        assert_eq!(todos[1].auto_project, "Next-release");
+        assert!(todos[1].task.finished && todos[3].task.finished && !todos[2].task.finished);
+        assert_eq!(todos[2].task.due_date, Some(Date::from_ymd(2018, 9, 1)));
+        assert_eq!(todos[2].task.contexts, vec!["desk"]);
+        assert_eq!(todos[2].subtasks.len(), 2);
+        assert_eq!(todos[2].id.line, 8);
+
+        let line = "  - [ ] ask  about the docs";
+        assert_eq!(set_checked(line, true), "  - [x] ask  about the docs");
+        assert_eq!(set_checked(&set_checked(line, true), false), line);
+        assert_eq!(set_checked("- [X] done", true), "- [X] done");
+        assert_eq!(set_checked("- not a task", true), "- not a task");
+    }
+
+    #[test]
+    fn remove_only_finished_items() {
+        let mut file = TodoFile::parse(NOTES);
+        // the changelog has an unticked subtask, so it stays, though its
+        // ticked one goes; code is left alone
+        assert_eq!(remove_finished(&mut file), 3);
+        let contents = file.to_string();
+        assert!(contents.contains("- [ ] write the changelog"));
+        assert!(!contents.contains("tag it") && !contents.contains("collect") && !contents.contains("announce"));
+
+        let mut file = TodoFile::parse("```\n- [x] example\n```\n");
+        assert_eq!(remove_finished(&mut file), 0);
+    }
+}
@@ -0,0 +1,182 @@
+//! Recurring tasks, as described by the `rec:` tag from the todo.txt
+//! extensions used by SimpleTask and topydo.
+//!
+//! `rec:1w` makes a new copy of a task one week after it was completed;
+//! `rec:+1w` ("strict") makes it one week after the old due date instead.
+//! Units are `d`ays, `w`eeks, `m`onths, `y`ears and `b`usiness days.
+
+use todo::Task;
+use datetools::{add_months, add_business_days};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unit {
+    Days,
+    Weeks,
+    Months,
+    Years,
+    BusinessDays,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Recurrence {
+    pub strict: bool,
+    pub count: u32,
+    pub unit: Unit,
+}
+
+impl ::std::str::FromStr for Recurrence
+{
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Recurrence, ()> {
+        let (strict, rest) = if s.starts_with('+') {
+            (true, &s[1..])
+        } else {
+            (false, s)
+        };
+
+        let unit = match rest.chars().last() {
+            Some('d') => Unit::Days,
+            Some('w') => Unit::Weeks,
+            Some('m') => Unit::Months,
+            Some('y') => Unit::Years,
+            Some('b') => Unit::BusinessDays,
+            _ => return Err(()),
+        };
+
+        let digits = &rest[..rest.len() - 1];
+        let count = if digits.is_empty() {
+            1
+        } else {
+            digits.parse().map_err(|_| ())?
+        };
+
+        if count == 0 {
+            return Err(());
+        }
+
+        Ok(Recurrence { strict, count, unit })
+    }
+}
+
+impl Recurrence {
+    pub fn for_task(task: &Task) -> Option<Recurrence> {
+        task.tags.get("rec").and_then(|r| r.parse().ok())
+    }
+
+    pub fn advance(&self, date: &::Date) -> ::Date {
+        let count = self.count as i64;
+        match self.unit {
+            Unit::Days => *date + ::time::Duration::days(count),
+            Unit::Weeks => *date + ::time::Duration::weeks(count),
+            Unit::Months => add_months(date, self.count as i32),
+            Unit::Years => add_months(date, self.count as i32 * 12),
+            Unit::BusinessDays => add_business_days(date, self.count),
+        }
+    }
+
+    /// Builds the next instance of `task`, which was completed on `today`.
+    ///
+    /// The due date moves forward by the recurrence interval, and a threshold
+    /// date keeps the same distance from the due date that it had before. A
+    /// task with neither date gets a due date. The copy gets back the priority
+    /// that completing the old task moved into its `pri:` tag. It's a new task,
+    /// so it doesn't keep the old one's `id:` tag, which needs to stay unique,
+    /// or its `spent:` time.
+    pub fn next_task(&self, task: &Task, today: &::Date) -> Task {
+        let mut next = task.clone();
+        next.finished = false;
+        next.finish_date = None;
+        next.tags.remove("id");
+        next.tags.remove("spent");
+        // finishing a task moves its priority into a `pri:` tag
+        if task.finished {
+            let priority = next.tags.get("pri").filter(|p| p.len() == 1).map(|p| p.as_bytes()[0]);
+            if let Some(p @ b'A'..=b'Z') = priority {
+                next.priority = p - b'A';
+                next.tags.remove("pri");
+            }
+        }
+        if next.create_date.is_some() {
+            next.create_date = Some(*today);
+        }
+
+        let base = |date: Option<::Date>| if self.strict {
+            date.unwrap_or(*today)
+        } else {
+            *today
+        };
+
+        match (task.due_date, task.threshold_date) {
+            (Some(due), threshold) => {
+                let new_due = self.advance(&base(Some(due)));
+                next.due_date = Some(new_due);
+                next.threshold_date = threshold.map(|t| new_due - due.signed_duration_since(t));
+            },
+            (None, Some(threshold)) => {
+                next.threshold_date = Some(self.advance(&base(Some(threshold))));
+            },
+            (None, None) => {
+                next.due_date = Some(self.advance(&base(None)));
+            },
+        }
+
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn date(s: &str) -> ::Date {
+        ::Date::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn parse_recurrence() {
+        assert_eq!(Recurrence::from_str("1w"), Ok(Recurrence { strict: false, count: 1, unit: Unit::Weeks }));
+        assert_eq!(Recurrence::from_str("+2m"), Ok(Recurrence { strict: true, count: 2, unit: Unit::Months }));
+        assert_eq!(Recurrence::from_str("b"), Ok(Recurrence { strict: false, count: 1, unit: Unit::BusinessDays }));
+        assert!(Recurrence::from_str("").is_err());
+        assert!(Recurrence::from_str("3x").is_err());
+        assert!(Recurrence::from_str("0d").is_err());
+    }
+
+    #[test]
+    fn advance_dates() {
+        let monthly: Recurrence = "1m".parse().unwrap();
+        assert_eq!(monthly.advance(&date("2018-01-31")), date("2018-02-28"));
+
+        let business: Recurrence = "2b".parse().unwrap();
+        // a Friday
+        assert_eq!(business.advance(&date("2018-05-04")), date("2018-05-08"));
+    }
+
+    #[test]
+    fn next_task_shifts_dates() {
+        let today = date("2018-05-10");
+        let task = Task::from_str("x 2018-05-09 2018-05-01 water plants due:2018-05-08 t:2018-05-06 rec:+1w pri:C").unwrap();
+
+        let strict = Recurrence::for_task(&task).unwrap().next_task(&task, &today);
+        assert!(!strict.finished);
+        assert_eq!(strict.finish_date, None);
+        assert_eq!(strict.create_date, Some(today));
+        assert_eq!(strict.priority, 2);
+        assert!(!strict.tags.contains_key("pri"));
+        assert_eq!(strict.due_date, Some(date("2018-05-15")));
+        assert_eq!(strict.threshold_date, Some(date("2018-05-13")));
+
+        let task = Task::from_str("call mom rec:3d").unwrap();
+        let loose = Recurrence::for_task(&task).unwrap().next_task(&task, &today);
+        assert_eq!(loose.due_date, Some(date("2018-05-13")));
+        assert_eq!(loose.tags.get("rec").map(|s| s.as_str()), Some("3d"));
+
+        let task = Task::from_str("x 2018-05-09 (B) review budget id:budget spent:2h est:3h rec:1m").unwrap();
+        let next = Recurrence::for_task(&task).unwrap().next_task(&task, &today);
+        assert!(!next.tags.contains_key("id"));
+        assert!(!next.tags.contains_key("spent"));
+        assert_eq!(next.tags.get("est").map(|s| s.as_str()), Some("3h"));
+    }
+}
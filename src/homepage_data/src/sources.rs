@@ -0,0 +1,149 @@
+//! `local:` entries whose paths are globs.
+//!
+//! A `path` like `~/src/*/TODO.txt` stands for every file it matches, each
+//! shown as a file of its own. In `name`, `auto_project` and `git`, `{dir}`
+//! is replaced with the name of the directory the match is in:
+//!
+//! ```yaml
+//! local:
+//!   - path: ~/src/*/TODO.txt
+//!     name: "{dir}"
+//!     auto_project: "{dir}"
+//!     git: "~/src/{dir}"
+//!     todos: true
+//! ```
+//!
+//! A file that's also listed on its own keeps its own entry. Matches are
+//! looked for again whenever the store refreshes, and every directory a new
+//! match could turn up in is watched, so new files show up without a
+//! restart. With `kind: directory`, the pattern matches directories instead.
+
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+
+use LocalFileDesc;
+
+pub fn is_glob(path: &str) -> bool {
+    path.contains(|c| c == '*' || c == '?' || c == '[')
+}
+
+fn fill_template(template: &str, dir: &str) -> String {
+    template.replace("{dir}", dir)
+}
+
+/// What `pattern` matches, in order, or nothing if it isn't a pattern.
+fn glob_paths(pattern: &str) -> Vec<PathBuf> {
+    match ::glob::glob(pattern) {
+        Ok(paths) => paths.filter_map(Result::ok).collect(),
+        // the config check reports these
+        Err(_) => vec![],
+    }
+}
+
+/// `sources` with each glob replaced by an entry for every match.
+pub fn expand(sources: &[LocalFileDesc]) -> Vec<LocalFileDesc> {
+    let listed:HashSet<String> = sources.iter()
+        .filter(|s| !is_glob(&s.path))
+        .map(LocalFileDesc::expanded_path)
+        .collect();
+    let mut seen = HashSet::new();
+    let mut expanded = vec![];
+    for source in sources {
+        if !is_glob(&source.path) {
+            expanded.push(source.clone());
+            continue;
+        }
+        for path in glob_paths(&source.expanded_path()) {
+            if source.is_directory() != path.is_dir() {
+                continue;
+            }
+            let path = path.to_string_lossy().to_string();
+            if listed.contains(&path) || !seen.insert(path.clone()) {
+                continue;
+            }
+            let dir = Path::new(&path).parent()
+                .and_then(Path::file_name)
+                .map(|d| d.to_string_lossy().to_string())
+                .unwrap_or_default();
+            expanded.push(LocalFileDesc {
+                name: fill_template(&source.name, &dir),
+                auto_project: fill_template(&source.auto_project, &dir),
+                git: fill_template(&source.git, &dir),
+                path,
+                ..source.clone()
+            });
+        }
+    }
+    expanded
+}
+
+/// The directories a new match for any of the globs in `sources` could
+/// turn up in: the one the first wildcard is in, and everything the pattern
+/// matches from there down to the last directory.
+pub fn watch_dirs(sources: &[LocalFileDesc]) -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    for source in sources.iter().filter(|s| is_glob(&s.path)) {
+        let pattern = source.expanded_path();
+        // split the way the platform does, so `\` counts on Windows
+        let components:Vec<Component> = Path::new(&pattern).components().collect();
+        let first_wildcard = match components.iter().position(|c| is_glob(&c.as_os_str().to_string_lossy())) {
+            Some(i) => i,
+            None => continue,
+        };
+        for i in first_wildcard..components.len() {
+            let prefix:PathBuf = components[..i].iter().collect();
+            if prefix.as_os_str().is_empty() {
+                continue;
+            }
+            let prefix_str = prefix.to_string_lossy().to_string();
+            if is_glob(&prefix_str) {
+                dirs.extend(glob_paths(&prefix_str).into_iter().filter(|p| p.is_dir()));
+            } else if prefix.is_dir() {
+                dirs.push(prefix);
+            }
+        }
+    }
+    dirs.sort();
+    dirs.dedup();
+    dirs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+
+    #[test]
+    fn expand_globs() {
+        let dir = ::tempfile::tempdir().unwrap();
+        for repo in &["alpha", "beta", "gamma"] {
+            fs::create_dir(dir.path().join(repo)).unwrap();
+        }
+        File::create(dir.path().join("alpha").join("TODO.txt")).unwrap();
+        File::create(dir.path().join("beta").join("TODO.txt")).unwrap();
+        let beta = dir.path().join("beta").join("TODO.txt");
+
+        let sources: Vec<LocalFileDesc> = ::serde_yaml::from_str(&format!("
+- path: {}
+  name: beta's own
+- path: {}/*/TODO.txt
+  name: \"{{dir}}\"
+  auto_project: \"{{dir}}\"
+  git: \"{}/{{dir}}\"
+  todos: true
+", beta.display(), dir.path().display(), dir.path().display())).unwrap();
+
+        let expanded = expand(&sources);
+        let names:Vec<&str> = expanded.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["beta's own", "alpha"]);
+        assert_eq!(expanded[1].path, dir.path().join("alpha").join("TODO.txt").to_string_lossy());
+        assert_eq!(expanded[1].auto_project, "alpha");
+        assert_eq!(expanded[1].git, dir.path().join("alpha").to_string_lossy());
+        assert!(expanded[1].todos);
+
+        // a new TODO.txt could turn up in any of the repos, or in a new one
+        let mut expected:Vec<PathBuf> = vec![dir.path().to_path_buf()];
+        expected.extend(["alpha", "beta", "gamma"].iter().map(|r| dir.path().join(r)));
+        assert_eq!(watch_dirs(&sources), expected);
+    }
+}
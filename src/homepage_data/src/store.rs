@@ -0,0 +1,473 @@
+//! Keeping what the page is built from in memory.
+//!
+//! Building the page means reading the config, every todo file and its
+//! history, the deadlines and the timers, and looking at each git repo. The
+//! store keeps all of that between requests, each with the `FileState` of the
+//! file it came from, and only reads a file again once its state has changed.
+//!
+//! Once `watch` has been called, the directories those files are in are
+//! watched, along with directory sources themselves, and files are only
+//! checked again after something in them has changed, so most requests don't
+//! touch the disk at all. Without a watcher every request checks the state of
+//! every file, which is still much cheaper than reading them, and lists every
+//! directory source again, which is about as cheap.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use notify::{self, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+use atomic::file_state;
+use diagnostics::Diagnostic;
+use timetrack::{self, Timers};
+use directory::{self, DirectoryItem};
+use git::{self, RepoStatus};
+use sources;
+use {config, deps, tree};
+use history::{self, History, Snapshot};
+use {goals, CachedData, Deadlines, FileState, HomepageMeta, LocalFileDesc, LocalFileDescWithState, TaskWithContext};
+
+/// How long to wait for changes to settle before reading them.
+const WATCH_DELAY_MS: u64 = 100;
+
+lazy_static! {
+    static ref STORE: Mutex<Store> = Mutex::new(Store::default());
+}
+
+fn lock() -> MutexGuard<'static, Store> {
+    // a panic while reading (a bad git repo, say) shouldn't take the page
+    // down for good; everything's read again after one anyway
+    STORE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Something read from a file, with the state the file was in then.
+struct Cached<T> {
+    path: String,
+    state: Option<FileState>,
+    value: T,
+}
+
+/// `cached`, or what `read` makes of `path` if it's changed since then.
+fn read_if_changed<T, F>(cached: Option<Cached<T>>, path: &str, state: Option<FileState>, read: F) -> Result<Cached<T>, ::failure::Error>
+    where F: FnOnce(&str) -> Result<T, ::failure::Error>
+{
+    match cached {
+        Some(ref cached) if cached.path == path && cached.state == state => {},
+        _ => return Ok(Cached { path: path.to_string(), state: state.clone(), value: read(path)? }),
+    }
+    Ok(cached.unwrap())
+}
+
+struct TodoFileData {
+    tasks: Vec<TaskWithContext>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+#[derive(Default)]
+pub struct Store {
+    meta: Option<Cached<HomepageMeta>>,
+    /// The config's `local` entries with globs expanded, which can change
+    /// without the config changing.
+    local: Vec<LocalFileDesc>,
+    /// Where new matches for those globs could turn up.
+    glob_dirs: Vec<PathBuf>,
+    /// By expanded path.
+    todo_files: HashMap<String, Cached<TodoFileData>>,
+    histories: HashMap<String, Cached<Vec<Snapshot>>>,
+    /// The items of directory sources, by expanded path.
+    directories: HashMap<String, Vec<DirectoryItem>>,
+    /// By repo, keyed on the state of its `git::watched_paths` and the files
+    /// in it; `None` if it couldn't be read.
+    repos: HashMap<String, Cached<Option<RepoStatus>>>,
+    deadlines: Option<Cached<Deadlines>>,
+    timers: Option<Cached<Timers>>,
+    /// Configured files, directories and repos that aren't there, shown on
+    /// the page until they turn up.
+    missing: Vec<Diagnostic>,
+    /// Set while the watcher would have told us about any changes.
+    fresh: bool,
+    watcher: Option<RecommendedWatcher>,
+    watched: HashSet<PathBuf>,
+    /// Every file we've read from, as the watcher names them.
+    inputs: HashSet<PathBuf>,
+    /// Where file histories are kept, if not in the usual place.
+    history_path: Option<PathBuf>,
+}
+
+impl Store {
+    /// Reads whatever has changed since we last looked.
+    fn refresh(&mut self) -> Result<(), ::failure::Error> {
+        if self.fresh {
+            return Ok(());
+        }
+        let result = config::path().and_then(|path| self.read_changed(&path.to_string_lossy()));
+        self.fresh = result.is_ok() && self.watcher.is_some();
+        self.update_watches();
+        result
+    }
+
+    fn read_changed(&mut self, config_path: &str) -> Result<(), ::failure::Error> {
+        let state = file_state(config_path)?;
+        let previous = self.meta.take();
+        let config_changed = match previous {
+            Some(ref m) => m.path != config_path || m.state != state,
+            None => true,
+        };
+        let meta = read_if_changed(previous, config_path, state, config::load_from)?;
+        if config_changed {
+            // how todo files are read depends on their config
+            self.todo_files.clear();
+        }
+
+        let history_path = self.history_path.clone().unwrap_or_else(history::default_path);
+        let file_history = History::new(&history_path, meta.value.history.clone());
+        let mut todo_files = HashMap::new();
+        let mut histories = HashMap::new();
+        let mut directories = HashMap::new();
+        let mut missing = vec![];
+        let local = sources::expand(&meta.value.local);
+        for local_file in &local {
+            let path = local_file.expanded_path();
+            if !Path::new(&path).exists() {
+                missing.push(Diagnostic::missing(&path));
+                continue;
+            }
+            let snapshots = if local_file.is_directory() {
+                let items = directory::list(local_file)?;
+                let state = directory::state(local_file, &items)?;
+                let snapshots = read_if_changed(self.histories.remove(&path), &path, Some(state.clone()),
+                    |path| file_history.record(path, state, || directory::metrics(local_file, &items)))?;
+                directories.insert(path.clone(), items);
+                snapshots
+            } else {
+                let state = file_state(&path)?;
+                if local_file.todos {
+                    let cached = read_if_changed(self.todo_files.remove(&path), &path, state.clone(), |_| {
+                        let mut diagnostics = vec![];
+                        let tasks = ::read_tasks(local_file, &mut diagnostics)?;
+                        Ok(TodoFileData { tasks, diagnostics })
+                    })?;
+                    todo_files.insert(path.clone(), cached);
+                }
+                read_if_changed(self.histories.remove(&path), &path, state,
+                    |path| file_history.update(path, local_file.task_format()))?
+            };
+            histories.insert(path, snapshots);
+        }
+
+        let mut repos:HashMap<String, Cached<Option<RepoStatus>>> = HashMap::new();
+        for local_file in local.iter().filter(|f| !f.git.is_empty()) {
+            let dir = ::shellexpand::tilde(&local_file.git).to_string();
+            if repos.contains_key(&dir) || missing.iter().any(|m| m.file == dir) {
+                continue;
+            }
+            if !Path::new(&dir).exists() {
+                missing.push(Diagnostic::missing(&dir));
+                continue;
+            }
+            // editing the files in it is the usual way a repo gets dirty, so
+            // that's looked out for as well as commits
+            let files = local.iter()
+                .filter(|f| f.git == local_file.git)
+                .filter_map(|f| histories.get(&f.expanded_path()).and_then(|h| h.state.clone()));
+            let state = git::repo_state(&dir)?.map(|repo| files.fold(repo, |a, b| FileState {
+                modification_time: a.modification_time.max(b.modification_time),
+                size: a.size + b.size,
+            }));
+            // without a repo there's nothing to tell when it changes
+            let cached = if state.is_some() { self.repos.remove(&dir) } else { None };
+            let repo = read_if_changed(cached, &dir, state, |dir| match git::status(dir) {
+                Ok(status) => Ok(Some(status)),
+                Err(e) => {
+                    eprintln!("could not read git repo {}: {}", dir, e);
+                    Ok(None)
+                },
+            })?;
+            repos.insert(dir, repo);
+        }
+
+        let deadlines_path = ::shellexpand::tilde(&meta.value.deadlines).to_string();
+        let deadlines = read_if_changed(self.deadlines.take(), &deadlines_path, file_state(&deadlines_path)?, ::read_deadlines)?;
+        let timers_path = ::shellexpand::tilde(::TIMERS_PATH).to_string();
+        let timers = read_if_changed(self.timers.take(), &timers_path, file_state(&timers_path)?, Timers::load)?;
+
+        self.glob_dirs = sources::watch_dirs(&meta.value.local);
+        self.local = local;
+        self.meta = Some(meta);
+        self.todo_files = todo_files;
+        self.histories = histories;
+        self.directories = directories;
+        self.repos = repos;
+        self.deadlines = Some(deadlines);
+        self.timers = Some(timers);
+        self.missing = missing;
+        Ok(())
+    }
+
+    /// Every file the page is built from, including config files that would
+    /// be used if they existed.
+    fn input_paths(&self) -> Vec<PathBuf> {
+        let mut paths = config::candidates();
+        paths.extend(self.todo_files.keys().map(PathBuf::from));
+        paths.extend(self.histories.keys().map(PathBuf::from));
+        paths.extend(self.directories.keys().map(PathBuf::from));
+        paths.extend(self.glob_dirs.iter().cloned());
+        paths.extend(self.repos.keys().flat_map(|dir| git::watched_paths(dir)));
+        paths.extend(self.deadlines.iter().map(|c| PathBuf::from(&c.path)));
+        paths.extend(self.missing.iter().map(|m| PathBuf::from(&m.file)));
+        paths.push(PathBuf::from(::shellexpand::tilde(::TIMERS_PATH).to_string()));
+
+        // changes to a symlinked file show up where it really is
+        let real:Vec<PathBuf> = paths.iter().filter_map(|p| p.canonicalize().ok()).collect();
+        paths.extend(real);
+        paths
+    }
+
+    /// Watches the directories of every input, directory sources themselves
+    /// and where globs could match, and stops watching any that have none
+    /// anymore.
+    fn update_watches(&mut self) {
+        if self.watcher.is_none() {
+            return;
+        }
+        self.inputs = self.input_paths().into_iter().collect();
+        // files are replaced by renaming over them, which ends a watch on
+        // the file itself, so it's their directories that are watched
+        let dirs:HashSet<PathBuf> = self.inputs.iter()
+            .filter_map(|p| p.parent())
+            .chain(self.directories.keys().map(Path::new))
+            .chain(self.glob_dirs.iter().map(PathBuf::as_path))
+            .filter(|dir| dir.is_dir())
+            .map(Path::to_path_buf)
+            .collect();
+
+        let watcher = self.watcher.as_mut().unwrap();
+        for dir in self.watched.difference(&dirs) {
+            let _ = watcher.unwatch(dir);
+        }
+        let mut watched = HashSet::new();
+        for dir in dirs {
+            if self.watched.contains(&dir) {
+                watched.insert(dir);
+                continue;
+            }
+            match watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                Ok(()) => { watched.insert(dir); },
+                Err(e) => eprintln!("could not watch {}: {}", dir.display(), e),
+            }
+        }
+        self.watched = watched;
+    }
+
+    /// Whether `path` is an input, or in a directory source or one a glob
+    /// could match in.
+    fn is_input(&self, path: &Path) -> bool {
+        match path.parent() {
+            Some(dir) if self.inputs.contains(dir) => true,
+            _ => self.inputs.contains(path),
+        }
+    }
+
+    /// Whether `event` could have changed anything on the page.
+    fn affects_inputs(&self, event: &DebouncedEvent) -> bool {
+        match *event {
+            DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => false,
+            DebouncedEvent::Create(ref path) | DebouncedEvent::Write(ref path) |
+            DebouncedEvent::Chmod(ref path) | DebouncedEvent::Remove(ref path) => self.is_input(path),
+            DebouncedEvent::Rename(ref from, ref to) => self.is_input(from) || self.is_input(to),
+            DebouncedEvent::Rescan | DebouncedEvent::Error(..) => true,
+        }
+    }
+
+    /// Puts the page's data together from what's in memory. Only this part
+    /// depends on the time, so it's done for every request.
+    fn data(&self, files_to_include: &[String]) -> Result<CachedData, ::failure::Error> {
+        if self.meta.is_none() {
+            return Err(format_err!("the config hasn't been read"));
+        }
+        let empty_timers = Timers::default();
+        let timers = self.timers.as_ref().map_or(&empty_timers, |t| &t.value);
+
+        // blocking depends on tasks in every file, including hidden ones
+        let all_tasks:Vec<TaskWithContext> = self.todo_files.values()
+            .flat_map(|f| f.value.tasks.iter().cloned())
+            .collect();
+        let open = deps::open_ids(&all_tasks);
+
+        let mut todos_count:usize = 0;
+        let mut all_todos:Vec<TaskWithContext> = vec![];
+        let mut files:Vec<LocalFileDescWithState> = vec![];
+        let mut diagnostics:Vec<Diagnostic> = self.missing.clone();
+        for local_file in &self.local {
+            let path = local_file.expanded_path();
+            let history = self.histories.get(&path).map(|h| h.value.clone()).unwrap_or_default();
+
+            let file_is_showing_todos = local_file.todos && if local_file.hide_in_index {
+                files_to_include.contains(&local_file.name)
+            } else {
+                files_to_include.is_empty() || files_to_include.contains(&local_file.name)
+            };
+
+            if let (true, Some(file)) = (file_is_showing_todos, self.todo_files.get(&path)) {
+                let mut todos = file.value.tasks.clone();
+                deps::mark_blocked(&mut todos, &open);
+                timetrack::mark_running(&mut todos, timers);
+                todos_count += todos.iter()
+                    .flat_map(|t| t.with_subtasks())
+                    .filter(|c| !c.task.finished && c.task.priority == 0 && !c.task.starts_in_future() && c.blocked_by.is_empty())
+                    .count();
+                all_todos.extend(todos);
+                diagnostics.extend(file.value.diagnostics.iter().cloned());
+            }
+
+            let git = self.repos.get(&::shellexpand::tilde(&local_file.git).to_string())
+                .and_then(|c| c.value.clone());
+            let (update_state, unmet_goals) = goals::check(local_file, &history, git.as_ref(), SystemTime::now());
+            let streaks = goals::streaks(local_file, &history, git.as_ref(), SystemTime::now());
+
+            files.push(LocalFileDescWithState {
+                desc: local_file.clone(),
+                history,
+                update_state,
+                unmet_goals,
+                streaks,
+                file_is_showing_todos,
+                git,
+                items: self.directories.get(&path).cloned().unwrap_or_default(),
+            });
+        }
+
+        let file_name = |path: &str| self.local.iter()
+            .find(|f| f.expanded_path() == path)
+            .map(|f| f.readable_name().to_string())
+            .unwrap_or_else(|| path.to_string());
+        let project_time = timetrack::rollups(&all_todos, &timers.sessions,
+            timetrack::projects, |s| s.projects.clone());
+        let file_time = timetrack::rollups(&all_todos, &timers.sessions,
+            |t| vec![file_name(&t.id.file)], |s| vec![file_name(&s.file)]);
+
+        let deadlines = match self.deadlines {
+            Some(ref deadlines) => ::upcoming_deadlines(&deadlines.value),
+            None => Deadlines::new(),
+        };
+
+        Ok(CachedData {
+            last_update: SystemTime::now(),
+            todos_count,
+            todos: tree::attach_tagged_subtasks(all_todos),
+            local_files: files,
+            deadlines,
+            diagnostics,
+            project_time,
+            file_time,
+            orphaned_timers: timetrack::orphaned(timers, &all_tasks),
+        })
+    }
+}
+
+/// The page's data, reading only what's changed since it was last asked for.
+pub fn data(files_to_include: &[String]) -> Result<CachedData, ::failure::Error> {
+    let mut store = lock();
+    store.refresh()?;
+    store.data(files_to_include)
+}
+
+/// Makes the store check its files again before it's next used. Anything
+/// that changes a file calls this, since the watcher could take a moment to
+/// notice.
+pub fn mark_stale() {
+    lock().fresh = false;
+}
+
+/// Starts watching the files the page is built from, and reads them again in
+/// the background whenever they change.
+pub fn watch() -> Result<(), ::failure::Error> {
+    let (tx, rx) = mpsc::channel();
+    let watcher = notify::watcher(tx, Duration::from_millis(WATCH_DELAY_MS))?;
+    {
+        let mut store = lock();
+        store.watcher = Some(watcher);
+        store.fresh = false;
+        store.refresh()?;
+    }
+
+    thread::spawn(move || {
+        for event in rx {
+            let mut store = lock();
+            if !store.affects_inputs(&event) {
+                continue;
+            }
+            store.fresh = false;
+            // do the reading now, rather than when the page is next loaded;
+            // any error is shown then
+            let _ = store.refresh();
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    #[test]
+    fn reads_only_changed_files() {
+        let dir = ::tempfile::tempdir().unwrap();
+        let todo = dir.path().join("todo.txt");
+        let notes = dir.path().join("notes.txt");
+        let config = dir.path().join("config.yaml");
+        File::create(&todo).unwrap().write_all(b"call bob\n").unwrap();
+        File::create(&notes).unwrap().write_all(b"some notes\n").unwrap();
+        File::create(&config).unwrap().write_all(format!(
+            "local:\n  - path: {}\n    name: todo\n    todos: true\n  - path: {}\n    name: notes\n",
+            todo.display(), notes.display()).as_bytes()).unwrap();
+
+        let history = dir.path().join("history.log");
+        let mut store = Store { history_path: Some(history.clone()), ..Store::default() };
+        store.read_changed(config.to_str().unwrap()).unwrap();
+        let data = store.data(&[]).unwrap();
+        assert_eq!(data.todos.len(), 1);
+        assert_eq!(data.local_files.len(), 2);
+
+        // a file whose state hasn't changed isn't looked at again, so it
+        // doesn't go back in the history
+        fs::remove_file(&history).unwrap();
+        File::create(&todo).unwrap().write_all(b"call bob\nbuy milk\n").unwrap();
+        store.read_changed(config.to_str().unwrap()).unwrap();
+        let log = ::get_file_contents(history.to_str().unwrap()).unwrap();
+        assert_eq!(log.lines().count(), 1);
+        assert!(!log.contains("notes.txt"));
+        assert_eq!(store.data(&[]).unwrap().todos.len(), 2);
+        assert_eq!(store.data(&["notes".to_string()]).unwrap().todos.len(), 0);
+    }
+    #[test]
+    fn missing_files_are_shown_with_the_rest() {
+        let dir = ::tempfile::tempdir().unwrap();
+        let todo = dir.path().join("todo.txt");
+        let gone = dir.path().join("gone.txt");
+        let config = dir.path().join("config.yaml");
+        File::create(&todo).unwrap().write_all(b"call bob\n").unwrap();
+        File::create(&config).unwrap().write_all(format!(
+            "local:\n  - path: {}\n    todos: true\n  - path: {}\n    todos: true\n    git: {}/repo\n",
+            todo.display(), gone.display(), dir.path().display()).as_bytes()).unwrap();
+
+        let mut store = Store { history_path: Some(dir.path().join("history.log")), ..Store::default() };
+        store.read_changed(config.to_str().unwrap()).unwrap();
+        let data = store.data(&[]).unwrap();
+        assert_eq!(data.todos.len(), 1);
+        let missing:Vec<String> = data.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(missing, vec![format!("{}: there's nothing here", gone.display()),
+                                 format!("{}/repo: there's nothing here", dir.path().display())]);
+
+        File::create(&gone).unwrap().write_all(b"buy milk\n").unwrap();
+        store.read_changed(config.to_str().unwrap()).unwrap();
+        let data = store.data(&[]).unwrap();
+        assert_eq!(data.todos.len(), 2);
+        assert_eq!(data.diagnostics.len(), 1);
+    }
+}
@@ -0,0 +1,234 @@
+//! Time tracking.
+//!
+//! Tasks can carry an estimate (`est:2h`) and the time spent on them so far
+//! (`spent:1h30m`). Timers started from the page add to `spent:` when they're
+//! stopped, and each stopped timer is also logged as a session, which is how
+//! we know how much time was spent this week.
+//!
+//! Starting a timer gives the task an `id:` tag if it doesn't have one, so
+//! that the timer still finds it after the task is changed or moved.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+use std::path::Path;
+
+use atomic::LockedFile;
+use identity::TaskId;
+use TaskWithContext;
+
+/// How long finished sessions are kept for.
+const SESSION_RETENTION_DAYS: u64 = 8 * 7;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RunningTimer {
+    pub id: TaskId,
+    /// The task's version when the timer was started.
+    pub version: String,
+    pub started: SystemTime,
+}
+
+impl RunningTimer {
+    /// Whether this timer is for the task `id` at `version`. Tasks with an
+    /// `id:` tag are matched by that; others, whose timers were started
+    /// before tasks got tags for them, by their contents.
+    pub fn is_for(&self, id: &TaskId, version: &str) -> bool {
+        self.id.file == id.file && if self.id.tag.is_empty() {
+            self.version == version
+        } else {
+            self.id.tag == id.tag
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed().unwrap_or_default()
+    }
+}
+
+/// Time spent on a task with a timer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Session {
+    pub file: String,
+    /// The task's projects, including its file's `auto_project`.
+    pub projects: Vec<String>,
+    pub subject: String,
+    pub ended: SystemTime,
+    pub seconds: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Timers {
+    #[serde(default)] pub running: Vec<RunningTimer>,
+    #[serde(default)] pub sessions: Vec<Session>,
+}
+
+impl Timers {
+    pub fn load(path: &str) -> Result<Timers, ::failure::Error> {
+        if !Path::new(path).exists() {
+            return Ok(Timers::default());
+        }
+        Timers::parse(path, &::get_file_contents(path)?)
+    }
+
+    /// Reads the timers from a file we've locked, to change them.
+    pub fn read(file: &LockedFile) -> Result<Timers, ::failure::Error> {
+        if file.state.is_none() {
+            return Ok(Timers::default());
+        }
+        Timers::parse(&file.path, &file.contents)
+    }
+
+    fn parse(path: &str, contents: &str) -> Result<Timers, ::failure::Error> {
+        Ok(::serde_yaml::from_str(contents)
+            .map_err(|e| format_err!("could not read timers from {}: {}", path, e))?)
+    }
+
+    pub fn save(&mut self, file: &mut LockedFile) -> Result<(), ::failure::Error> {
+        let retention = Duration::from_secs(SESSION_RETENTION_DAYS * 24 * 60 * 60);
+        self.sessions.retain(|s| s.ended.elapsed().map(|age| age < retention).unwrap_or(true));
+
+        if let Some(dir) = Path::new(&file.path).parent() {
+            ::std::fs::create_dir_all(dir)?;
+        }
+        let yaml = ::serde_yaml::to_string(self)
+            .map_err(|e| format_err!("could not convert timers to YAML: {}", e))?;
+        file.write(&yaml)
+    }
+
+    pub fn running_for(&self, id: &TaskId, version: &str) -> Option<usize> {
+        self.running.iter().position(|t| t.is_for(id, version))
+    }
+
+    pub fn is_running(&self, todo: &TaskWithContext) -> bool {
+        self.running_for(&todo.id, &todo.version).is_some()
+    }
+}
+
+/// The running timers whose tasks aren't in `todos` anymore.
+pub fn orphaned(timers: &Timers, todos: &[TaskWithContext]) -> Vec<RunningTimer> {
+    let todos:Vec<&TaskWithContext> = todos.iter().flat_map(|t| t.with_subtasks()).collect();
+    timers.running.iter()
+        .filter(|timer| !todos.iter().any(|t| timer.is_for(&t.id, &t.version)))
+        .cloned()
+        .collect()
+}
+
+/// Fills in `timer_running` for every task.
+pub fn mark_running(todos: &mut [TaskWithContext], timers: &Timers) {
+    for todo in todos.iter_mut() {
+        todo.timer_running = timers.is_running(todo);
+        mark_running(&mut todo.subtasks, timers);
+    }
+}
+
+/// The projects a task counts towards.
+pub fn projects(todo: &TaskWithContext) -> Vec<String> {
+    let mut projects = todo.task.projects.clone();
+    if !todo.auto_project.is_empty() && !projects.contains(&todo.auto_project) {
+        projects.push(todo.auto_project.clone());
+    }
+    projects
+}
+
+/// Midnight at the start of this Monday, local time.
+pub fn start_of_week() -> SystemTime {
+    use chrono::{Datelike, TimeZone};
+
+    let today = ::datetools::today();
+    let monday = today - ::time::Duration::days(today.weekday().num_days_from_monday() as i64);
+    let midnight = ::chrono::Local.from_local_date(&monday).earliest()
+        .map(|d| d.and_hms(0, 0, 0))
+        .unwrap_or_else(::chrono::Local::now);
+    SystemTime::from(midnight)
+}
+
+/// Estimates and time spent for a group of tasks.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TimeRollup {
+    pub name: String,
+    /// What's left of the estimates of unfinished tasks.
+    pub remaining: Duration,
+    pub spent: Duration,
+    pub spent_this_week: Duration,
+}
+
+/// Adds up the time in `todos` and this week's `sessions` into a rollup for
+/// each of the keys they belong to. Groups without any time are left out.
+pub fn rollups<F, G>(todos: &[TaskWithContext], sessions: &[Session], todo_keys: F, session_keys: G) -> Vec<TimeRollup>
+    where F: Fn(&TaskWithContext) -> Vec<String>,
+          G: Fn(&Session) -> Vec<String>,
+{
+    fn rollup(rollups: &mut BTreeMap<String, TimeRollup>, key: String) -> &mut TimeRollup {
+        rollups.entry(key.clone()).or_insert_with(|| TimeRollup { name: key, .. Default::default() })
+    }
+
+    let mut rollups:BTreeMap<String, TimeRollup> = BTreeMap::new();
+    for todo in todos.iter().flat_map(|t| t.with_subtasks()) {
+        let spent = todo.task.spent().unwrap_or_default();
+        let remaining = match todo.task.estimate() {
+            Some(ref estimate) if !todo.task.finished && *estimate > spent => *estimate - spent,
+            _ => Duration::default(),
+        };
+        for key in todo_keys(todo) {
+            let r = rollup(&mut rollups, key);
+            r.remaining += remaining;
+            r.spent += spent;
+        }
+    }
+
+    let week = start_of_week();
+    for session in sessions.iter().filter(|s| s.ended >= week) {
+        for key in session_keys(session) {
+            rollup(&mut rollups, key).spent_this_week += Duration::from_secs(session.seconds);
+        }
+    }
+
+    rollups.into_iter()
+        .map(|(_, r)| r)
+        .filter(|r| r != &TimeRollup { name: r.name.clone(), .. Default::default() })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use todo::Task;
+
+    fn todo(line: &str, auto_project: &str) -> TaskWithContext {
+        TaskWithContext::new("todo.txt", 0, line, Task::from_str(line).unwrap(), auto_project)
+    }
+
+    #[test]
+    fn rollups_by_project() {
+        let hours = |h: u64| Duration::from_secs(h * 3600);
+        let todos = vec![
+            todo("+api write endpoint est:3h spent:1h", "work"),
+            todo("x +api write docs est:2h spent:3h", "work"),
+            todo("water plants", "home"),
+        ];
+        let sessions = vec![
+            Session { file: "todo.txt".into(), projects: vec!["api".into()], subject: "write docs".into(), ended: SystemTime::now(), seconds: 3600 },
+            Session { file: "todo.txt".into(), projects: vec!["api".into()], subject: "old".into(), ended: SystemTime::now() - Duration::from_secs(8 * 24 * 3600), seconds: 3600 },
+        ];
+
+        let by_project = rollups(&todos, &sessions, projects, |s| s.projects.clone());
+        assert_eq!(by_project, vec![
+            TimeRollup { name: "api".into(), remaining: hours(2), spent: hours(4), spent_this_week: hours(1) },
+            TimeRollup { name: "work".into(), remaining: hours(2), spent: hours(4), spent_this_week: Duration::default() },
+        ]);
+    }
+
+    #[test]
+    fn timers_match_tasks() {
+        let todo = todo("write report id:report", "");
+        let timer = RunningTimer { id: todo.id.clone(), version: "old".into(), started: SystemTime::now() };
+        assert!(timer.is_for(&todo.id, &todo.version));
+
+        let untagged = RunningTimer { id: TaskId { file: "todo.txt".into(), line: 3, tag: String::new() }, version: "v1".into(), started: SystemTime::now() };
+        assert!(untagged.is_for(&TaskId { file: "todo.txt".into(), line: 4, tag: String::new() }, "v1"));
+        assert!(!untagged.is_for(&TaskId { file: "todo.txt".into(), line: 3, tag: String::new() }, "v2"));
+
+        let timers = Timers { running: vec![timer, untagged.clone()], sessions: vec![] };
+        assert_eq!(orphaned(&timers, &[todo]), vec![untagged]);
+    }
+}
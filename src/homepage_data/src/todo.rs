@@ -1,7 +1,8 @@
 use ::std::collections::BTreeMap;
+use ::std::ops::Range;
 use ::nom::rest_s;
-use std::hash::{Hash, Hasher};
-use std::collections::hash_map::DefaultHasher;
+use ::datetools::DateOrTime;
+use ::tokens::{tokenize, Spanned, Token};
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct Task {
@@ -12,6 +13,9 @@ pub struct Task {
     pub finished: bool,
     pub threshold_date: Option<::Date>,
     pub due_date: Option<::Date>,
+    /// The time of day the task is due, from `due:2018-05-03T15:00` or an
+    /// `at:15:00` tag.
+    pub due_time: Option<::chrono::NaiveTime>,
     pub contexts: Vec<String>,
     pub projects: Vec<String>,
     pub hashtags: Vec<String>,
@@ -19,10 +23,38 @@ pub struct Task {
 }
 
 impl Task {
-    pub fn calc_hash(&self) -> String {
-        let mut hasher = DefaultHasher::new();
-        self.hash(&mut hasher);
-        format!("{:x}", hasher.finish())
+    /// Whether the task has a threshold (`t:`) date that hasn't come yet.
+    pub fn starts_in_future(&self) -> bool {
+        match self.threshold_date {
+            Some(ref t) => match ::datetools::DateWhen::for_date(t) {
+                ::datetools::DateWhen::Future => true,
+                _ => false,
+            },
+            None => false,
+        }
+    }
+
+    pub fn due(&self) -> Option<DateOrTime> {
+        self.due_date.map(|date| match self.due_time {
+            Some(time) => DateOrTime::DateTime(date.and_time(time)),
+            None => DateOrTime::Date(date),
+        })
+    }
+
+    /// How long the task is expected to take, from its `est:` tag.
+    pub fn estimate(&self) -> Option<::std::time::Duration> {
+        self.duration_tag("est")
+    }
+
+    /// How long has been spent on the task so far, from its `spent:` tag.
+    pub fn spent(&self) -> Option<::std::time::Duration> {
+        self.duration_tag("spent")
+    }
+
+    /// Reads a tag like `2h` or `1h30m` the same way durations in the
+    /// config file are read.
+    fn duration_tag(&self, key: &str) -> Option<::std::time::Duration> {
+        self.tags.get(key).and_then(|value| ::humantime::parse_duration(value).ok())
     }
 
     pub fn priority_label(&self) -> String {
@@ -49,6 +81,7 @@ impl Default for Task
             finished: false,
             threshold_date: None,
             due_date: None,
+            due_time: None,
             contexts: Vec::new(),
             projects: Vec::new(),
             hashtags: Vec::new(),
@@ -57,11 +90,52 @@ impl Default for Task
     }
 }
 
+/// Why a line couldn't be read as a task.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    BadDate(String),
+    BadPriority(String),
+    BadTime(String),
+    BadDuration(String),
+    DuplicateTag(String),
+    Unparseable,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// One-based character column of the offending text.
+    pub column: usize,
+    pub kind: ParseErrorKind,
+}
+
+impl ::std::fmt::Display for ParseErrorKind
+{
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result
+    {
+        match *self {
+            ParseErrorKind::BadDate(ref date) => write!(f, "invalid date '{}'", date),
+            ParseErrorKind::BadTime(ref time) => write!(f, "invalid time '{}'", time),
+            ParseErrorKind::BadDuration(ref duration) => write!(f, "invalid duration '{}'", duration),
+            ParseErrorKind::BadPriority(ref priority) => write!(f, "malformed priority '{}'", priority),
+            ParseErrorKind::DuplicateTag(ref key) => write!(f, "duplicate '{}:' tag", key),
+            ParseErrorKind::Unparseable => write!(f, "could not parse task"),
+        }
+    }
+}
+
+impl ::std::fmt::Display for ParseError
+{
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result
+    {
+        write!(f, "column {}: {}", self.column, self.kind)
+    }
+}
+
 impl ::std::str::FromStr for Task
 {
-    type Err = ();
+    type Err = ParseError;
 
-    fn from_str(s: &str) -> Result<Task, ()> {
+    fn from_str(s: &str) -> Result<Task, ParseError> {
         task(&s.to_owned())
     }
 }
@@ -90,8 +164,14 @@ impl ::std::fmt::Display for Task
 
         f.write_str(self.subject.as_str())?;
 
-        if let Some(due_date) = self.due_date {
-            f.write_str(format!(" due:{}", due_date.format("%Y-%m-%d")).as_str())?;
+        match (self.due_date, self.due_time) {
+            (Some(due_date), Some(due_time)) =>
+                f.write_str(format!(" due:{}T{}", due_date.format("%Y-%m-%d"), due_time.format("%H:%M")).as_str())?,
+            (Some(due_date), None) =>
+                f.write_str(format!(" due:{}", due_date.format("%Y-%m-%d")).as_str())?,
+            (None, Some(due_time)) =>
+                f.write_str(format!(" at:{}", due_time.format("%H:%M")).as_str())?,
+            (None, None) => {},
         }
 
         if let Some(threshold_date) = self.threshold_date {
@@ -99,7 +179,7 @@ impl ::std::fmt::Display for Task
         }
 
         for (key, value) in &self.tags {
-            f.write_str(format!(" {}:{}", key, value).as_str())?;
+            write!(f, " {}", Token::Tag { key, value })?;
         }
 
         Ok(())
@@ -160,14 +240,14 @@ named!(priority<&str, u8>,
     )
 );
 
-fn get_tags(regex: &::regex::Regex, subject: &str) -> Vec<String>
+/// The lowercased names of the contexts, projects or hashtags in `tokens`,
+/// as picked out by `name`.
+fn get_tags<'a, F>(tokens: &[Spanned<'a>], name: F) -> Vec<String>
+    where F: Fn(&Token<'a>) -> Option<&'a str>
 {
-    let mut tags = regex.captures_iter(subject)
-        .map(|x| {
-            x["tag"].to_lowercase()
-                .to_string()
-        })
-        .filter(|x| !x.is_empty())
+    let mut tags = tokens.iter()
+        .filter_map(|t| name(&t.token))
+        .map(|x| x.to_lowercase())
         .collect::<Vec<_>>();
 
     tags.sort();
@@ -176,56 +256,33 @@ fn get_tags(regex: &::regex::Regex, subject: &str) -> Vec<String>
     tags
 }
 
-macro_rules! regex_tags_shared {
-    () => { "(?P<space>^|[\\s]){}(?P<tag>[\\w-]+)" }
-}
-
-fn get_contexts(subject: &str) -> Vec<String>
+/// Splits the `key:value` tags out of a subject. Each tag takes one of the
+/// spaces around it with it.
+fn get_keywords(tokens: &[Spanned]) -> (String, BTreeMap<String, String>)
 {
-    lazy_static! {
-        static ref REGEX: ::regex::Regex =
-            ::regex::Regex::new(&format!(regex_tags_shared!(), "@")).unwrap();
-    }
-    get_tags(&REGEX, subject)
-}
-
-fn get_projects(subject: &str) -> Vec<String>
-{
-    lazy_static! {
-        static ref REGEX: ::regex::Regex =
-            ::regex::Regex::new(&format!(regex_tags_shared!(), "\\+")).unwrap();
-    }
-    get_tags(&REGEX, subject)
-}
-
-fn get_hashtags(subject: &str) -> Vec<String>
-{
-    lazy_static! {
-        static ref REGEX: ::regex::Regex =
-            ::regex::Regex::new(&format!(regex_tags_shared!(), "#")).unwrap();
-    }
-    get_tags(&REGEX, subject)
-}
-
-fn get_keywords(subject: &str) -> (String, BTreeMap<String, String>)
-{
-    lazy_static! {
-        static ref REGEX: ::regex::Regex =
-            ::regex::Regex::new(r" (?P<key>[^\s]+):(?P<value>[^\s^/]+)").unwrap();
-    }
-
     let mut tags = BTreeMap::new();
+    let mut new_subject = String::new();
+    let mut skip_space = false;
+
+    for t in tokens {
+        match t.token {
+            Token::Tag { key, value } => {
+                tags.insert(key.to_owned(), value.to_owned());
+                if new_subject.ends_with(' ') {
+                    new_subject.pop();
+                } else {
+                    skip_space = true;
+                }
+            },
+            _ => {
+                let text = if skip_space && t.text.starts_with(' ') { &t.text[1..] } else { t.text };
+                new_subject.push_str(text);
+                skip_space = false;
+            },
+        }
+    }
 
-    let new_subject = REGEX.replace_all(subject, |caps: &::regex::Captures| {
-        let key = caps.name("key").unwrap().as_str();
-        let value = caps.name("value").unwrap().as_str();
-
-        tags.insert(key.to_owned(), value.to_owned());
-
-        String::new()
-    });
-
-    (new_subject.into_owned(), tags)
+    (new_subject, tags)
 }
 
 named!(parse<&str, Task>,
@@ -246,32 +303,40 @@ named!(parse<&str, Task>,
                     Some(priority) => priority,
                     None => 26,
                 },
-                create_date: if create_date.is_none() {
+                // A finished task's first date is when it was finished; an
+                // unfinished task's only date is when it was created.
+                create_date: if create_date.is_none() && finished.is_none() {
                     finish_date
                 } else {
                     create_date
                 },
-                finish_date: if create_date.is_none() {
+                finish_date: if create_date.is_none() && finished.is_none() {
                     None
                 } else {
                     finish_date
                 },
                 finished: finished.is_some(),
-                contexts: get_contexts(rest),
-                projects: get_projects(rest),
-                hashtags: get_hashtags(rest),
 
                 .. Default::default()
             };
 
-            let (subject, mut tags) = get_keywords(rest);
+            let tokens = tokenize(rest);
+            task.contexts = get_tags(&tokens, |t| match *t { Token::Context(name) => Some(name), _ => None });
+            task.projects = get_tags(&tokens, |t| match *t { Token::Project(name) => Some(name), _ => None });
+            task.hashtags = get_tags(&tokens, |t| match *t { Token::Hashtag(name) => Some(name), _ => None });
+
+            let (subject, mut tags) = get_keywords(&tokens);
             task.subject = subject;
 
             if let Some(due) = tags.remove("due") {
-                task.due_date = match ::Date::parse_from_str(due.as_str(), "%Y-%m-%d") {
-                    Ok(due) => Some(due),
-                    Err(_) => None,
-                };
+                if let Some((date, time)) = parse_due(&due) {
+                    task.due_date = Some(date);
+                    task.due_time = time;
+                }
+            }
+
+            if let Some(at) = tags.remove("at") {
+                task.due_time = parse_time(&at);
             }
 
             if let Some(t) = tags.remove("t") {
@@ -288,10 +353,248 @@ named!(parse<&str, Task>,
     )
 );
 
-pub fn task(line: &str) -> Result<Task, ()>
+fn parse_time(s: &str) -> Option<::chrono::NaiveTime>
 {
-    match parse(line) {
+    ::chrono::NaiveTime::parse_from_str(s, "%H:%M")
+        .or_else(|_| ::chrono::NaiveTime::parse_from_str(s, "%H:%M:%S"))
+        .ok()
+}
+
+/// Reads a `due:` value, which is a date with an optional time of day.
+fn parse_due(s: &str) -> Option<(::Date, Option<::chrono::NaiveTime>)>
+{
+    let mut parts = s.splitn(2, 'T');
+    let date = ::Date::parse_from_str(parts.next()?, "%Y-%m-%d").ok()?;
+    match parts.next() {
+        Some(time) => Some((date, Some(parse_time(time)?))),
+        None => Some((date, None)),
+    }
+}
+
+/// Byte ranges of the parts of a task line, for editing a line in place
+/// without re-serializing it. Each prefix range includes its trailing space.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct Spans {
+    /// Leading whitespace, which nests a task under the one above it.
+    pub indent: usize,
+    pub finished: Option<Range<usize>>,
+    pub priority: Option<Range<usize>>,
+    pub first_date: Option<Range<usize>>,
+    pub second_date: Option<Range<usize>>,
+    /// Where the subject (and its tags) begin.
+    pub body: usize,
+}
+
+/// A `key:value` tag in a task line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TagSpan {
+    pub key: String,
+    /// Just the value.
+    pub value: Range<usize>,
+    /// The whole tag, including the space in front of it.
+    pub whole: Range<usize>,
+}
+
+named!(finished_mark<&str, &str>, complete!(tag_s!("x ")));
+named!(priority_prefix<&str, u8>, complete!(priority));
+named!(date_prefix<&str, ::Date>, complete!(date));
+
+pub fn spans(line: &str) -> Spans
+{
+    let mut spans = Spans::default();
+    let mut rest = line.trim_left_matches(|c| c == ' ' || c == '\t');
+    spans.indent = line.len() - rest.len();
+
+    macro_rules! take_span {
+        ($parser:expr) => {{
+            let start = line.len() - rest.len();
+            match $parser(rest) {
+                ::nom::IResult::Done(remaining, _) => {
+                    rest = remaining;
+                    Some(start..line.len() - rest.len())
+                },
+                _ => None,
+            }
+        }}
+    }
+
+    spans.finished = take_span!(finished_mark);
+    spans.priority = take_span!(priority_prefix);
+    spans.first_date = take_span!(date_prefix);
+    if spans.first_date.is_some() {
+        spans.second_date = take_span!(date_prefix);
+    }
+    spans.body = line.len() - rest.len();
+    spans
+}
+
+pub fn tag_spans(line: &str) -> Vec<TagSpan>
+{
+    let body = spans(line).body;
+    let rest = &line[body..];
+    tokenize(rest).into_iter()
+        .filter_map(|t| match t.token {
+            Token::Tag { key, .. } => {
+                let start = body + t.range.start;
+                let end = body + t.range.end;
+                // the whole tag takes a space with it, like `get_keywords`
+                let whole = if line[..start].ends_with(' ') {
+                    start - 1..end
+                } else if line[end..].starts_with(' ') {
+                    start..end + 1
+                } else {
+                    start..end
+                };
+                Some(TagSpan {
+                    key: key.to_string(),
+                    value: start + key.len() + 1..end,
+                    whole,
+                })
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+fn error_at(line: &str, offset: usize, kind: ParseErrorKind) -> ParseError
+{
+    ParseError {
+        column: line[..offset].chars().count() + 1,
+        kind,
+    }
+}
+
+/// Finds the mistakes that the parser quietly turns into part of the
+/// subject, or drops.
+pub fn check(line: &str) -> Result<(), ParseError>
+{
+    lazy_static! {
+        static ref DATE_SHAPED: ::regex::Regex =
+            ::regex::Regex::new(r"^\d{4}-\d{2}-\d{2}(\s|$)").unwrap();
+        static ref PRIORITY_SHAPED: ::regex::Regex =
+            ::regex::Regex::new(r"^\([A-Za-z0-9]{1,2}\)(\s|$)").unwrap();
+    }
+
+    let spans = spans(line);
+    let body = &line[spans.body..];
+
+    if let Some(m) = DATE_SHAPED.find(body) {
+        let date = m.as_str().trim_right();
+        return Err(error_at(line, spans.body, ParseErrorKind::BadDate(date.to_string())));
+    }
+
+    let priority_start = spans.finished.as_ref().map(|r| r.end).unwrap_or(spans.indent);
+    let priority_text = match spans.priority {
+        Some(ref range) => Some(&line[range.clone()]),
+        None if spans.body == priority_start => PRIORITY_SHAPED.find(body).map(|m| m.as_str()),
+        None => None,
+    };
+    if let Some(priority) = priority_text {
+        let priority = priority.trim_right();
+        let letter = priority.as_bytes()[1];
+        if priority.len() != 3 || letter < b'A' || letter > b'Z' {
+            return Err(error_at(line, priority_start, ParseErrorKind::BadPriority(priority.to_string())));
+        }
+    }
+
+    let mut seen:Vec<String> = vec![];
+    for tag in tag_spans(line) {
+        let value = &line[tag.value.clone()];
+        let error = match tag.key.as_str() {
+            "due" if parse_due(value).is_none() => Some(ParseErrorKind::BadDate(value.to_string())),
+            "t" if ::Date::parse_from_str(value, "%Y-%m-%d").is_err() => Some(ParseErrorKind::BadDate(value.to_string())),
+            "at" if parse_time(value).is_none() => Some(ParseErrorKind::BadTime(value.to_string())),
+            "est" | "spent" if ::humantime::parse_duration(value).is_err() => Some(ParseErrorKind::BadDuration(value.to_string())),
+            "due" | "t" | "at" | "est" | "spent" => None,
+            _ => continue,
+        };
+
+        if seen.contains(&tag.key) {
+            return Err(error_at(line, tag.whole.start + 1, ParseErrorKind::DuplicateTag(tag.key)));
+        }
+        if let Some(error) = error {
+            return Err(error_at(line, tag.value.start, error));
+        }
+        seen.push(tag.key);
+    }
+
+    Ok(())
+}
+
+/// Reads a task as best it can; see `check` for what it got wrong.
+pub fn task(line: &str) -> Result<Task, ParseError>
+{
+    match parse(&line[spans(line).indent..]) {
         ::nom::IResult::Done(_, task) => Ok(task),
-        _ => Err(()),
+        _ => Err(error_at(line, 0, ParseErrorKind::Unparseable)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn error(line: &str) -> (usize, String) {
+        let err = check(line).expect_err(line);
+        (err.column, format!("{}", err.kind))
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(error("2018-13-40 a bad create date"), (1, "invalid date '2018-13-40'".into()));
+        assert_eq!(error("x 2018-05-01 2018-02-30 bad"), (14, "invalid date '2018-02-30'".into()));
+        assert_eq!(error("(a) lowercase priority"), (1, "malformed priority '(a)'".into()));
+        assert_eq!(error("x (AB) double priority"), (3, "malformed priority '(AB)'".into()));
+        assert_eq!(error("call bob due:2018-05-01 due:2018-05-02"), (25, "duplicate 'due:' tag".into()));
+        assert_eq!(error("café due:someday"), (10, "invalid date 'someday'".into()));
+
+        assert!(check("(A) 2018-05-01 fine due:2018-05-02 t:2018-05-01").is_ok());
+        assert!(check("a (parenthetical) remark").is_ok());
+
+        // the task is still read, without the parts that are wrong
+        let task = Task::from_str("café due:someday").unwrap();
+        assert_eq!(task.subject, "café");
+        assert_eq!(task.due_date, None);
+        assert_eq!(Task::from_str("(a) lowercase priority").unwrap().priority, 26);
+    }
+
+    #[test]
+    fn indented_tasks() {
+        let task = Task::from_str("\t  x (B) 2018-05-01 subtask due:2018-05-02").unwrap();
+        assert!(task.finished);
+        assert_eq!(task.priority, 1);
+        assert_eq!(task.subject, "subtask");
+
+        let spans = spans("\t  x subtask");
+        assert_eq!(spans.indent, 3);
+        assert_eq!(spans.finished, Some(3..5));
+
+        assert_eq!(error("  (a) subtask").0, 3);
+    }
+
+    #[test]
+    fn due_times() {
+        let task = Task::from_str("submit form due:2018-05-03T15:00").unwrap();
+        assert_eq!(task.due(), Some(DateOrTime::DateTime(::Date::from_ymd(2018, 5, 3).and_hms(15, 0, 0))));
+        assert_eq!(format!("{}", task), "submit form due:2018-05-03T15:00");
+
+        let task = Task::from_str("call bob at:9:30 due:2018-05-03").unwrap();
+        assert_eq!(task.due(), Some(DateOrTime::DateTime(::Date::from_ymd(2018, 5, 3).and_hms(9, 30, 0))));
+        assert!(!task.tags.contains_key("at"));
+
+        assert_eq!(Task::from_str("call bob due:2018-05-03").unwrap().due(),
+                   Some(DateOrTime::Date(::Date::from_ymd(2018, 5, 3))));
+        assert_eq!(error("call bob at:noon"), (13, "invalid time 'noon'".into()));
+        assert_eq!(error("call bob due:2018-05-03T25:00"), (14, "invalid date '2018-05-03T25:00'".into()));
+    }
+
+    #[test]
+    fn estimates() {
+        let task = Task::from_str("write report est:2h spent:1h30m").unwrap();
+        assert_eq!(task.estimate(), Some(::std::time::Duration::from_secs(2 * 3600)));
+        assert_eq!(task.spent(), Some(::std::time::Duration::from_secs(90 * 60)));
+        assert_eq!(Task::from_str("write report").unwrap().estimate(), None);
+        assert_eq!(error("write report est:a while"), (18, "invalid duration 'a'".into()));
     }
 }
@@ -0,0 +1,195 @@
+//! Splitting the body of a task line (everything after its priority and
+//! dates) into tokens.
+//!
+//! The parser, `Task`'s `Display` impl and the page's HTML all go through
+//! this, so they agree on what's a project, a tag or a link. Each word is
+//! looked at on its own, and a URL wins over everything else, so the `@` and
+//! `+` inside one don't turn into contexts and projects.
+
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Token<'a> {
+    /// Anything that isn't one of the others, including whitespace.
+    Text(&'a str),
+    /// `@phone`, without the `@`.
+    Context(&'a str),
+    /// `+project`, without the `+`.
+    Project(&'a str),
+    /// `#hashtag`, without the `#`.
+    Hashtag(&'a str),
+    /// `key:value`.
+    Tag { key: &'a str, value: &'a str },
+    Url(&'a str),
+    Date(::Date),
+}
+
+/// A token, with the text it came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Spanned<'a> {
+    pub token: Token<'a>,
+    pub text: &'a str,
+    /// Where `text` is in the string that was tokenized.
+    pub range: Range<usize>,
+}
+
+impl<'a> fmt::Display for Token<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Text(text) | Token::Url(text) => f.write_str(text),
+            Token::Context(name) => write!(f, "@{}", name),
+            Token::Project(name) => write!(f, "+{}", name),
+            Token::Hashtag(name) => write!(f, "#{}", name),
+            Token::Tag { key, value } => write!(f, "{}:{}", key, value),
+            Token::Date(ref date) => write!(f, "{}", date.format("%Y-%m-%d")),
+        }
+    }
+}
+
+lazy_static! {
+    static ref URL: ::regex::Regex =
+        ::regex::Regex::new(r"[a-zA-Z][a-zA-Z0-9+.-]*://\S+").unwrap();
+    static ref MARKED: ::regex::Regex =
+        ::regex::Regex::new(r"^[@+#]([\w-]+)").unwrap();
+    static ref TAG: ::regex::Regex =
+        ::regex::Regex::new(r"^([^\s:]+):(\S+)$").unwrap();
+    static ref DATE: ::regex::Regex =
+        ::regex::Regex::new(r"^\d{4}-\d{2}-\d{2}$").unwrap();
+}
+
+/// Splits one word into tokens, with ranges relative to the word.
+fn word_tokens<'a>(word: &'a str) -> Vec<(Token<'a>, Range<usize>)> {
+    if let Some(m) = URL.find(word) {
+        // punctuation right after a link is almost always part of the
+        // sentence rather than the link
+        let url = m.as_str().trim_right_matches(|c| ".,;:!?)]'\"".contains(c));
+        let end = m.start() + url.len();
+
+        let mut tokens = vec![];
+        if m.start() > 0 {
+            tokens.push((Token::Text(&word[..m.start()]), 0..m.start()));
+        }
+        tokens.push((Token::Url(url), m.start()..end));
+        if end < word.len() {
+            tokens.push((Token::Text(&word[end..]), end..word.len()));
+        }
+        return tokens;
+    }
+
+    if let Some(caps) = MARKED.captures(word) {
+        let name = caps.get(1).unwrap().as_str();
+        let end = caps.get(0).unwrap().end();
+        let token = match word.as_bytes()[0] {
+            b'@' => Token::Context(name),
+            b'+' => Token::Project(name),
+            _ => Token::Hashtag(name),
+        };
+
+        let mut tokens = vec![(token, 0..end)];
+        if end < word.len() {
+            tokens.push((Token::Text(&word[end..]), end..word.len()));
+        }
+        return tokens;
+    }
+
+    if let Some(caps) = TAG.captures(word) {
+        let key = caps.get(1).unwrap().as_str();
+        let value = caps.get(2).unwrap().as_str();
+        return vec![(Token::Tag { key, value }, 0..word.len())];
+    }
+
+    if DATE.is_match(word) {
+        if let Ok(date) = ::Date::parse_from_str(word, "%Y-%m-%d") {
+            return vec![(Token::Date(date), 0..word.len())];
+        }
+    }
+
+    vec![(Token::Text(word), 0..word.len())]
+}
+
+pub fn tokenize<'a>(s: &'a str) -> Vec<Spanned<'a>> {
+    let mut words = vec![];
+    let mut start = 0;
+    while start < s.len() {
+        let rest = &s[start..];
+        let is_space = rest.starts_with(char::is_whitespace);
+        let len = rest.find(|c: char| c.is_whitespace() != is_space).unwrap_or(rest.len());
+
+        if is_space {
+            words.push((Token::Text(&rest[..len]), start..start + len));
+        } else {
+            for (token, range) in word_tokens(&rest[..len]) {
+                words.push((token, start + range.start..start + range.end));
+            }
+        }
+        start += len;
+    }
+
+    // keep runs of plain text together
+    let mut tokens:Vec<Spanned> = vec![];
+    for (token, range) in words {
+        let follows_text = match (&token, tokens.last()) {
+            (&Token::Text(_), Some(&Spanned { token: Token::Text(_), .. })) => true,
+            _ => false,
+        };
+        if follows_text {
+            let last = tokens.last_mut().unwrap();
+            last.range.end = range.end;
+            last.text = &s[last.range.clone()];
+            last.token = Token::Text(last.text);
+        } else {
+            tokens.push(Spanned { token, text: &s[range.clone()], range });
+        }
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(s: &str) -> Vec<Token> {
+        tokenize(s).into_iter().map(|t| t.token).collect()
+    }
+
+    #[test]
+    fn tokenize_a_task() {
+        assert_eq!(tokens("call @Bob re +work, due:2018-05-03 #q2"), vec![
+            Token::Text("call "),
+            Token::Context("Bob"),
+            Token::Text(" re "),
+            Token::Project("work"),
+            Token::Text(", "),
+            Token::Tag { key: "due", value: "2018-05-03" },
+            Token::Text(" "),
+            Token::Hashtag("q2"),
+        ]);
+    }
+
+    #[test]
+    fn urls_win() {
+        assert_eq!(tokens("see (https://example.com/a+b@c/?q=1). ref:docs/api"), vec![
+            Token::Text("see ("),
+            Token::Url("https://example.com/a+b@c/?q=1"),
+            Token::Text("). "),
+            Token::Tag { key: "ref", value: "docs/api" },
+        ]);
+    }
+
+    #[test]
+    fn spans_cover_the_input() {
+        let s = "  a  @b c:d  2018-05-01 2018-13-01 ";
+        let spanned = tokenize(s);
+        assert_eq!(spanned.iter().map(|t| t.text).collect::<String>(), s);
+        for t in &spanned {
+            assert_eq!(&s[t.range.clone()], t.text);
+        }
+        let dates:Vec<&Token> = spanned.iter()
+            .map(|t| &t.token)
+            .filter(|t| match **t { Token::Date(_) => true, _ => false })
+            .collect();
+        assert_eq!(dates, vec![&Token::Date(::Date::from_ymd(2018, 5, 1))]);
+    }
+}
@@ -0,0 +1,200 @@
+//! Subtasks.
+//!
+//! A task is a subtask of the closest line above it with less indentation,
+//! or, if it has a `parent:` tag, of the task with the matching `id:` tag in
+//! any todo file. Blank lines don't break up a group of subtasks.
+
+use std::collections::HashSet;
+
+use identity::TaskId;
+use todo::spans;
+use TaskWithContext;
+
+fn is_blank(line: &str) -> bool {
+    line.trim().is_empty()
+}
+
+fn indent(line: &str) -> usize {
+    spans(line).indent
+}
+
+/// Nests tasks (with their indentation) under the task above them with less
+/// indentation.
+pub fn nest_by_indent(tasks: Vec<(usize, TaskWithContext)>) -> Vec<TaskWithContext> {
+    fn pop(stack: &mut Vec<(usize, TaskWithContext)>, roots: &mut Vec<TaskWithContext>) {
+        let (_, todo) = stack.pop().unwrap();
+        match stack.last_mut() {
+            Some(&mut (_, ref mut parent)) => parent.subtasks.push(todo),
+            None => roots.push(todo),
+        }
+    }
+
+    let mut roots = vec![];
+    let mut stack:Vec<(usize, TaskWithContext)> = vec![];
+    for (indent, todo) in tasks {
+        while stack.last().map_or(false, |&(i, _)| i >= indent) {
+            pop(&mut stack, &mut roots);
+        }
+        stack.push((indent, todo));
+    }
+    while !stack.is_empty() {
+        pop(&mut stack, &mut roots);
+    }
+
+    roots
+}
+
+fn find_by_tag_mut<'a>(todos: &'a mut Vec<TaskWithContext>, id: &str) -> Option<&'a mut TaskWithContext> {
+    for todo in todos.iter_mut() {
+        if todo.task.tags.get("id").map(|s| s.as_str()) == Some(id) {
+            return Some(todo);
+        }
+        if let Some(found) = find_by_tag_mut(&mut todo.subtasks, id) {
+            return Some(found);
+        }
+    }
+    None
+}
+
+/// Moves top-level tasks with a `parent:` tag under the task with that `id:`,
+/// which may be in another file. Tags that don't match anything, or that
+/// would make a cycle, are left alone.
+pub fn attach_tagged_subtasks(todos: Vec<TaskWithContext>) -> Vec<TaskWithContext> {
+    let (mut pending, mut roots): (Vec<_>, Vec<_>) = todos.into_iter()
+        .partition(|t| t.task.tags.contains_key("parent"));
+
+    // A parent may itself be waiting to be attached, so keep going until
+    // nothing else finds a home.
+    loop {
+        let mut unattached = vec![];
+        let count = pending.len();
+        for todo in pending {
+            let parent_id = todo.task.tags["parent"].clone();
+            match find_by_tag_mut(&mut roots, &parent_id) {
+                Some(parent) => parent.subtasks.push(todo),
+                None => unattached.push(todo),
+            }
+        }
+        pending = unattached;
+        if pending.len() == count {
+            break;
+        }
+    }
+
+    roots.extend(pending);
+    roots
+}
+
+/// The index just past the last line nested under `index`.
+pub fn subtree_end<S: AsRef<str>>(lines: &[S], index: usize) -> usize {
+    let own_indent = indent(lines[index].as_ref());
+    let mut end = index + 1;
+    for (j, line) in lines.iter().enumerate().skip(index + 1) {
+        let line = line.as_ref();
+        if is_blank(line) {
+            continue;
+        }
+        if indent(line) <= own_indent {
+            break;
+        }
+        end = j + 1;
+    }
+    end
+}
+
+/// The task that the task `id` is a subtask of, if any.
+fn parent_of<'a>(todos: &'a [TaskWithContext], id: &TaskId) -> Option<&'a TaskWithContext> {
+    todos.iter()
+        .flat_map(|t| t.with_subtasks())
+        .find(|t| t.subtasks.iter().any(|s| s.id == *id))
+}
+
+/// If every subtask of the parent of the task `id` is finished but the
+/// parent isn't, returns the parent, so the user can be asked whether to
+/// finish it too. Subtasks in other files only count if `todos` have had
+/// `attach_tagged_subtasks` done to them.
+pub fn parent_to_offer<'a>(todos: &'a [TaskWithContext], id: &TaskId) -> Option<&'a TaskWithContext> {
+    let parent = parent_of(todos, id)?;
+    if parent.task.finished {
+        return None;
+    }
+    if parent.with_subtasks()[1..].iter().all(|t| t.task.finished) {
+        Some(parent)
+    } else {
+        None
+    }
+}
+
+/// The `id:` tags of tasks that are unfinished or have unfinished subtasks,
+/// which in `todos` with their tagged subtasks attached can be in another
+/// file.
+pub fn unfinished_ids(todos: &[TaskWithContext]) -> HashSet<String> {
+    todos.iter()
+        .flat_map(|t| t.with_subtasks())
+        .filter(|t| t.with_subtasks().iter().any(|s| !s.task.finished))
+        .filter_map(|t| t.task.tags.get("id").cloned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use todo::Task;
+
+    static LINES: [&str; 7] = [
+        "plan the trip id:trip",
+        "  book flights",
+        "    x compare prices",
+        "",
+        "  x book hotel",
+        "pack parent:trip",
+        "unrelated",
+    ];
+
+    /// The tasks in `lines` of `file`, nested.
+    fn nest(file: &str, lines: &[&str]) -> Vec<TaskWithContext> {
+        let tasks = lines.iter()
+            .enumerate()
+            .filter(|&(_, line)| !is_blank(line))
+            .map(|(i, line)| (indent(line), TaskWithContext::new(file, i, line, Task::from_str(line).unwrap(), "")))
+            .collect();
+        nest_by_indent(tasks)
+    }
+
+    fn id(line: usize) -> TaskId {
+        TaskId { file: "todo.txt".into(), line, tag: String::new() }
+    }
+
+    #[test]
+    fn nesting_by_lines() {
+        assert_eq!(subtree_end(&LINES, 0), 5);
+        assert_eq!(subtree_end(&LINES, 1), 3);
+
+        let todos = attach_tagged_subtasks(nest("todo.txt", &LINES));
+        assert_eq!(todos.len(), 2);
+        assert_eq!(todos[0].subtasks.len(), 3);
+        assert_eq!(todos[0].subtasks[0].subtasks.len(), 1);
+        assert_eq!(todos[0].subtasks[2].task.subject, "pack");
+    }
+
+    #[test]
+    fn offer_parent_when_children_are_done() {
+        let todos = attach_tagged_subtasks(nest("todo.txt", &LINES));
+        assert_eq!(parent_to_offer(&todos, &id(2)).map(|t| t.id.line), Some(1));
+        assert_eq!(parent_to_offer(&todos, &id(4)), None);
+
+        let mut lines = LINES.to_vec();
+        lines[1] = "  x book flights";
+        lines[5] = "x pack parent:trip";
+        let todos = attach_tagged_subtasks(nest("todo.txt", &lines));
+        assert_eq!(parent_to_offer(&todos, &id(4)).map(|t| t.id.line), Some(0));
+
+        // a subtask in another file counts too
+        let mut todos = nest("todo.txt", &lines);
+        todos.extend(nest("errands.txt", &["buy sunscreen parent:trip"]));
+        let todos = attach_tagged_subtasks(todos);
+        assert_eq!(parent_to_offer(&todos, &id(4)), None);
+        assert!(unfinished_ids(&todos).contains("trip"));
+    }
+}
@@ -0,0 +1,124 @@
+//! Small SVG charts of file history, drawn here so the page doesn't need any
+//! charting scripts.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::UNIX_EPOCH;
+
+use chrono::{self, Datelike, NaiveDate};
+use homepage_data::history::Snapshot;
+
+const SPARKLINE_WIDTH: f64 = 60.0;
+const SPARKLINE_HEIGHT: f64 = 14.0;
+
+const CELL: i64 = 11;
+const CELL_GAP: i64 = 2;
+/// Room for the weekday and month labels.
+const LEFT: i64 = 28;
+const TOP: i64 = 16;
+const WEEKS: i64 = 53;
+const COLORS: [&str; 5] = ["#ebedf0", "#c6e48b", "#7bc96f", "#239a3b", "#196127"];
+
+/// A line of the file's size over time, or nothing if it hasn't changed
+/// enough times to draw one.
+pub fn sparkline(history: &[Snapshot]) -> String {
+    if history.len() < 2 {
+        return String::new();
+    }
+    let secs = |s: &Snapshot| s.state.modification_time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as f64).unwrap_or(0.0);
+    let (first, last) = (secs(&history[0]), secs(&history[history.len() - 1]));
+    let min = history.iter().map(|s| s.state.size).min().unwrap_or(0) as f64;
+    let max = history.iter().map(|s| s.state.size).max().unwrap_or(0) as f64;
+
+    let mut points = vec![];
+    for (i, snapshot) in history.iter().enumerate() {
+        let x = if last > first {
+            (secs(snapshot) - first) / (last - first)
+        } else {
+            i as f64 / (history.len() - 1) as f64
+        };
+        let y = if max > min { (snapshot.state.size as f64 - min) / (max - min) } else { 0.5 };
+        // leave half the stroke inside the box at the top and bottom
+        points.push(format!("{:.1},{:.1}", x * SPARKLINE_WIDTH, 0.5 + (1.0 - y) * (SPARKLINE_HEIGHT - 1.0)));
+    }
+
+    format!(concat!(r#"<svg class="sparkline" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+                    r#"<title>{n} changes, {min} to {max} bytes</title>"#,
+                    r#"<polyline fill="none" stroke="currentColor" stroke-width="1" points="{points}"/></svg>"#),
+            w = SPARKLINE_WIDTH, h = SPARKLINE_HEIGHT, n = history.len(), min = min, max = max,
+            points = points.join(" "))
+}
+
+/// The number of changes to any of the files on each day, by local date.
+pub fn changes_per_day<'a, I: IntoIterator<Item = &'a Snapshot>>(snapshots: I) -> BTreeMap<NaiveDate, usize> {
+    let mut counts = BTreeMap::new();
+    for snapshot in snapshots {
+        let date = chrono::DateTime::<chrono::Local>::from(snapshot.state.modification_time).naive_local().date();
+        *counts.entry(date).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// A calendar of the last year with a square for each day, darker the more
+/// changes there were that day, a column for each week.
+pub fn heatmap(counts: &BTreeMap<NaiveDate, usize>, today: NaiveDate) -> String {
+    let this_week = today - chrono::Duration::days(i64::from(today.weekday().num_days_from_sunday()));
+    let start = this_week - chrono::Duration::weeks(WEEKS - 1);
+    let max = counts.range(start..).map(|(_, &n)| n).max().unwrap_or(0);
+
+    let width = LEFT + WEEKS * (CELL + CELL_GAP);
+    let height = TOP + 7 * (CELL + CELL_GAP);
+    let mut svg = format!(r#"<svg class="heatmap" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#, w = width, h = height);
+    for &(row, name) in &[(1, "Mon"), (3, "Wed"), (5, "Fri")] {
+        let _ = write!(svg, r#"<text x="0" y="{}">{}</text>"#, TOP + row * (CELL + CELL_GAP) + CELL - 1, name);
+    }
+
+    let mut date = start;
+    while date <= today {
+        let week = (date - start).num_days() / 7;
+        let day = i64::from(date.weekday().num_days_from_sunday());
+        let x = LEFT + week * (CELL + CELL_GAP);
+        if date.day() <= 7 && day == 0 {
+            let _ = write!(svg, r#"<text x="{}" y="{}">{}</text>"#, x, TOP - 4, date.format("%b"));
+        }
+
+        let count = counts.get(&date).cloned().unwrap_or(0);
+        let level = if count == 0 { 0 } else { (count * 4 + max - 1) / max };
+        let _ = write!(svg,
+            r#"<rect x="{}" y="{}" width="{c}" height="{c}" fill="{}"><title>{} change{} on {}</title></rect>"#,
+            x, TOP + day * (CELL + CELL_GAP), COLORS[level], count, if count == 1 { "" } else { "s" },
+            date.format("%Y-%m-%d"), c = CELL);
+        date = date + chrono::Duration::days(1);
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use homepage_data::FileState;
+    use std::time::Duration;
+
+    #[test]
+    fn draw_charts() {
+        let snapshot = |secs: u64, size: u64| Snapshot {
+            state: FileState { modification_time: UNIX_EPOCH + Duration::from_secs(secs), size },
+            metrics: None,
+        };
+        assert_eq!(sparkline(&[snapshot(0, 10)]), "");
+        let svg = sparkline(&[snapshot(0, 10), snapshot(50, 30), snapshot(100, 20)]);
+        assert!(svg.contains(r#"points="0.0,13.5 30.0,0.5 60.0,7.0""#), "{}", svg);
+
+        let today = NaiveDate::from_ymd(2018, 8, 15);
+        let mut counts = BTreeMap::new();
+        counts.insert(today, 4);
+        counts.insert(NaiveDate::from_ymd(2018, 8, 14), 1);
+        let svg = heatmap(&counts, today);
+        // every day from the Sunday 52 weeks before this week's up to today
+        assert_eq!(svg.matches("<rect").count(), 52 * 7 + 4);
+        assert!(svg.contains(r##"fill="#196127"><title>4 changes on 2018-08-15</title>"##));
+        assert!(svg.contains(r##"fill="#c6e48b"><title>1 change on 2018-08-14</title>"##));
+    }
+}
@@ -1,29 +1,32 @@
-#[macro_use] extern crate lazy_static;
 extern crate askama;
 extern crate serde;
 extern crate chrono;
 extern crate homepage_data;
-extern crate regex;
-extern crate linkify;
 extern crate time;
 extern crate failure;
 
 use homepage_data::{CachedData, LocalFileDescWithState, Deadlines, TaskWithContext};
 use homepage_data::todo::Task;
+use homepage_data::diagnostics::Diagnostic;
+use homepage_data::timetrack::{TimeRollup, RunningTimer};
+use homepage_data::backups::Backup;
 use homepage_data::datetools::{DateWhen, duration_relative_to_today};
 use homepage_data::datetools;
 
 use askama::Template;
 
+pub mod charts;
+
 pub struct RenderOpts {
     show_priority_text_label: bool,
 }
 
 pub mod filters {
-    use regex::Regex;
-    use ::{askama, std, linkify};
+    use ::{askama, std};
     use time;
-    use datetools::DateWhen;
+    use datetools::{DateWhen, DateOrTime};
+    use homepage_data::tokens::{tokenize, Token};
+    use homepage_data::history::Snapshot;
     
     pub fn date_when_css_class(d: &::chrono::NaiveDate) -> askama::Result<String> {
         Ok(match DateWhen::for_date(d) {
@@ -33,23 +36,92 @@ pub mod filters {
         }.into())
     }
 
-    pub fn spanify(d: &std::fmt::Display) -> askama::Result<String> {
-        lazy_static! {
-            static ref CONTEXT_REGEX: Regex = Regex::new(r"@(\w+)").unwrap();
-            static ref PROJECT_REGEX: Regex = Regex::new(r"\+(\w+)").unwrap();
+    pub fn when_css_class(d: &DateOrTime) -> askama::Result<String> {
+        Ok(match d.date_when() {
+            DateWhen::Future => "future",
+            DateWhen::Past => "past",
+            DateWhen::Today => "today"
+        }.into())
+    }
+
+    fn escape(s: &str) -> String {
+        let mut escaped = String::with_capacity(s.len());
+        for c in s.chars() {
+            match c {
+                '&' => escaped.push_str("&amp;"),
+                '<' => escaped.push_str("&lt;"),
+                '>' => escaped.push_str("&gt;"),
+                '"' => escaped.push_str("&quot;"),
+                '\'' => escaped.push_str("&#x27;"),
+                c => escaped.push(c),
+            }
         }
+        escaped
+    }
+
+    /// Whether a URL in a task is safe to link to. Todo files can come from
+    /// anywhere, so `javascript:` and other schemes that could do something
+    /// on this page stay text.
+    fn is_web_url(url: &str) -> bool {
+        let url = url.to_lowercase();
+        url.starts_with("http://") || url.starts_with("https://")
+    }
+
+    /// Renders a task's subject as HTML, with links for its contexts,
+    /// projects and web URLs.
+    pub fn subject_html(d: &std::fmt::Display) -> askama::Result<String> {
         let s = format!("{}", d);
-        let t = CONTEXT_REGEX
-            .replace_all(&s, r#"<a class="todo-context" href="?context=$1">$0</a>"#);
-        let r = PROJECT_REGEX
-            .replace_all(&t, r#"<a class="todo-project" href="?project=$1">$0</a>"#);
-        Ok(String::from(r))
+        let mut html = String::new();
+        for t in tokenize(&s) {
+            html.push_str(&match t.token {
+                Token::Context(name) => format!(r#"<a class="todo-context" href="?context={}">{}</a>"#,
+                                                name.to_lowercase(), escape(t.text)),
+                Token::Project(name) => format!(r#"<a class="todo-project" href="?project={}">{}</a>"#,
+                                                name.to_lowercase(), escape(t.text)),
+                Token::Hashtag(_) => format!(r#"<span class="todo-hashtag">{}</span>"#, escape(t.text)),
+                Token::Url(url) if is_web_url(url) => format!(r#"<a href="{}">{}</a>"#, escape(url), escape(url)),
+                _ => escape(t.text),
+            });
+        }
+        Ok(html)
     }
 
     pub fn humanize_date(date: &::chrono::NaiveDate) -> askama::Result<String> {
         humanize_signed_duration(&super::duration_relative_to_today(date))
     }
 
+    /// Like `humanize_date`, but a time of day within the next or last day
+    /// reads as "3 hours from now", and further off as "tomorrow at 15:00".
+    pub fn humanize_when(d: &DateOrTime) -> askama::Result<String> {
+        match *d {
+            DateOrTime::Date(ref date) => humanize_date(date),
+            DateOrTime::DateTime(ref datetime) => {
+                let duration = d.duration_relative_to_now();
+                if duration.num_hours().abs() < 24 {
+                    humanize_signed_duration(&duration)
+                } else {
+                    Ok(format!("{} at {}", humanize_date(&datetime.date())?, datetime.format("%H:%M")))
+                }
+            },
+        }
+    }
+
+    pub fn short_duration(duration: &std::time::Duration) -> askama::Result<String> {
+        Ok(super::datetools::format_duration(duration))
+    }
+
+    /// An SVG line of the file's size over time.
+    pub fn sparkline(history: &[Snapshot]) -> askama::Result<String> {
+        Ok(super::charts::sparkline(history))
+    }
+
+    /// A streak's length, like "5 days" or "12 hours".
+    pub fn streak_length(duration: &std::time::Duration) -> askama::Result<String> {
+        let hours = duration.as_secs() / (60 * 60);
+        let (n, unit) = if hours >= 24 { (hours / 24, "day") } else { (hours, "hour") };
+        Ok(format!("{} {}{}", n, unit, if n == 1 { "" } else { "s" }))
+    }
+
     pub fn humanize_duration(duration: &std::time::Duration) -> askama::Result<String> {
         let d = time::Duration::from_std(*duration).unwrap();
         humanize_signed_duration(&d)
@@ -97,42 +169,158 @@ pub mod filters {
         Ok(string)
     }
 
-    pub fn linkify(d: &std::fmt::Display) -> askama::Result<String> {
-        let s = format!("{}", d);
-        let mut finder = linkify::LinkFinder::new();
-        finder.kinds(&[linkify::LinkKind::Url]);
-        let mut last = 0;
-        let mut result = String::new();
-        for link in finder.links(&s) {
-            result.push_str(&s[last .. link.start()]);
-            result.push_str(&format!("<a href=\"{}\">{}</a>", link.as_str(), link.as_str()));
-            last = link.end();
-        }
-        if last == 0 {
-            result = s.to_string();
-        } else {
-            result.push_str(&s[last ..]);
-        }
-        Ok(result.to_owned())
-    }
 }
 
 #[derive(Template)]
 #[template(path = "hello.html")]
 struct HelloTemplate<'a> {
     todos_count: usize,
+    upcoming_count: usize,
+    show_future: bool,
     local_files: &'a Vec<LocalFileDescWithState>,
-    todos: &'a Vec<TaskWithContext>,
+    todos: &'a Vec<TodoRow<'a>>,
+    /// The tasks that start in the future, if they're shown.
+    upcoming: &'a Vec<TodoRow<'a>>,
     deadlines: &'a Deadlines,
+    diagnostics: &'a Vec<Diagnostic>,
+    orphaned_timers: &'a Vec<RunningTimer>,
+    project_time: &'a Vec<TimeRollup>,
+    file_time: &'a Vec<TimeRollup>,
+    render_opts: &'a RenderOpts,
+}
+
+#[derive(Template)]
+#[template(path = "todo_item.html")]
+struct TodoItemTemplate<'a> {
+    row: &'a TodoRow<'a>,
     render_opts: &'a RenderOpts,
 }
 
+/// A task in the todo list, which shows subtasks indented under their parent.
+struct TodoRow<'a> {
+    todo: &'a TaskWithContext,
+    depth: usize,
+}
+
+fn flatten_todos<'a>(todos: &'a [TaskWithContext], depth: usize, rows: &mut Vec<TodoRow<'a>>) {
+    for todo in todos {
+        rows.push(TodoRow { todo, depth });
+        flatten_todos(&todo.subtasks, depth + 1, rows);
+    }
+}
+
+fn sort_todos_by_key<K: Ord, F: Fn(&TaskWithContext) -> K>(todos: &mut Vec<TaskWithContext>, f: &F) {
+    todos.sort_by_key(|t| f(t));
+    for todo in todos.iter_mut() {
+        sort_todos_by_key(&mut todo.subtasks, f);
+    }
+}
+
+/// Takes the unfinished tasks whose threshold date hasn't come yet out of
+/// `todos`, subtasks and all.
+fn take_upcoming(todos: &mut Vec<TaskWithContext>) -> Vec<TaskWithContext> {
+    let (mut upcoming, rest): (Vec<_>, Vec<_>) = todos.drain(..)
+        .partition(|t| !t.task.finished && t.task.starts_in_future());
+    *todos = rest;
+    for todo in todos.iter_mut() {
+        upcoming.extend(take_upcoming(&mut todo.subtasks));
+    }
+    upcoming
+}
+
+struct BackupRow<'a> {
+    backup: &'a Backup,
+    created: String,
+    file_name: String,
+    shown: bool,
+}
+
+#[derive(Template)]
+#[template(path = "backups.html")]
+struct BackupsTemplate<'a> {
+    rows: Vec<BackupRow<'a>>,
+    shown: Option<&'a Backup>,
+    contents: &'a str,
+}
+
+/// Renders the list of backups, and the contents of one of them if `shown`
+/// is given.
+pub fn render_backups(backups: &[Backup], shown: Option<(&Backup, &str)>) -> Result<String, failure::Error> {
+    let rows = backups.iter()
+        .map(|backup| BackupRow {
+            backup,
+            created: chrono::DateTime::<chrono::Local>::from(backup.created).format("%Y-%m-%d %H:%M:%S").to_string(),
+            file_name: std::path::Path::new(&backup.source).file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| backup.source.clone()),
+            shown: shown.map_or(false, |(b, _)| b.id == backup.id),
+        })
+        .collect();
+
+    let template = BackupsTemplate {
+        rows,
+        shown: shown.map(|(b, _)| b),
+        contents: shown.map(|(_, contents)| contents).unwrap_or(""),
+    };
+
+    Ok(template.render().unwrap())
+}
+
+struct ActivityRow<'a> {
+    file: &'a LocalFileDescWithState,
+    changes_this_year: usize,
+}
+
+#[derive(Template)]
+#[template(path = "activity.html")]
+struct ActivityTemplate<'a> {
+    heatmap: String,
+    changes_this_year: usize,
+    rows: Vec<ActivityRow<'a>>,
+}
+
+/// Renders a calendar of changes to all the files over the last year, and a
+/// sparkline for each file.
+pub fn render_activity(local_files: &[LocalFileDescWithState]) -> Result<String, failure::Error> {
+    let today = chrono::Local::today().naive_local();
+    let year_ago = today - chrono::Duration::days(365);
+    let count_this_year = |counts: &std::collections::BTreeMap<chrono::NaiveDate, usize>|
+        counts.range(year_ago..).map(|(_, &n)| n).sum();
+
+    let counts = charts::changes_per_day(local_files.iter().flat_map(|f| f.history.iter()));
+    let rows = local_files.iter()
+        .map(|file| ActivityRow {
+            file,
+            changes_this_year: count_this_year(&charts::changes_per_day(&file.history)),
+        })
+        .collect();
+
+    let template = ActivityTemplate {
+        heatmap: charts::heatmap(&counts, today),
+        changes_this_year: count_this_year(&counts),
+        rows,
+    };
+
+    Ok(template.render().unwrap())
+}
+
+/// Renders a single top-level `<li>` for the todo list, for inserting tasks
+/// into an already loaded page.
+pub fn render_todo_item(todo: &TaskWithContext) -> Result<String, failure::Error> {
+    let item = TodoItemTemplate {
+        row: &TodoRow { todo, depth: 0 },
+        render_opts: &RenderOpts { show_priority_text_label: false },
+    };
+
+    Ok(item.render().unwrap())
+}
+
 fn due_date_sort(a: &Task) -> i32 {
     if a.finished {
         0 // finished tasks don't sort by their due date
     } else {
-        match &a.due_date {
-            Some(due_date) => match DateWhen::for_date(due_date) {
+        match a.due() {
+            Some(due) => match due.date_when() {
                 DateWhen::Future => 0,
                 DateWhen::Today => -1,
                 DateWhen::Past => -2,
@@ -147,6 +335,8 @@ pub struct SearchParams {
     pub project: String,
     pub search: String,
     pub sort_by: String,
+    /// Include tasks whose threshold date hasn't come yet.
+    pub show_future: bool,
 }
 
 impl Default for SearchParams {
@@ -156,6 +346,7 @@ impl Default for SearchParams {
             project: String::new(),
             search: String::new(),
             sort_by: String::new(),
+            show_future: false,
         }
     }
 }
@@ -169,23 +360,42 @@ pub fn render(
 
     let mut todos_sorted = cached_data.todos.clone();
 
-    todos_sorted.sort_by_key(|a| { (due_date_sort(&a.task), a.task.priority) });
+    let upcoming_count = todos_sorted.iter()
+        .flat_map(|t| t.with_subtasks())
+        .filter(|t| !t.task.finished && t.task.starts_in_future())
+        .count();
+    // tasks that can't be started yet get a list of their own, in the order
+    // they start
+    let mut upcoming = take_upcoming(&mut todos_sorted);
+    if !query_params.show_future {
+        upcoming.clear();
+    }
+    sort_todos_by_key(&mut upcoming, &|a| { (a.task.threshold_date, due_date_sort(&a.task), a.task.priority) });
+
+    // blocked tasks go below everything that can be worked on now
+    sort_todos_by_key(&mut todos_sorted, &|a| { (!a.blocked_by.is_empty(), due_date_sort(&a.task), a.task.priority) });
 
-    // filter
+    // filter; a task stays, along with all of its subtasks, if it or any of
+    // its subtasks match
     {
-        if !query_params.context.is_empty() {
-            todos_sorted.retain(|t| t.task.contexts.contains(&query_params.context));
-        }
-        if !query_params.project.is_empty() {
-            todos_sorted.retain(|t| t.task.projects.contains(&query_params.project) || t.auto_project == query_params.project);
-        }
-        if !query_params.search.is_empty() {
-            todos_sorted.retain(|t| t.task.subject.contains(&query_params.search));
+        let retain_matching = |todos: &mut Vec<TaskWithContext>, f: &Fn(&TaskWithContext) -> bool| {
+            todos.retain(|t| t.with_subtasks().into_iter().any(|t| f(t)));
+        };
+        for todos in &mut [&mut todos_sorted, &mut upcoming] {
+            if !query_params.context.is_empty() {
+                retain_matching(todos, &|t| t.task.contexts.contains(&query_params.context));
+            }
+            if !query_params.project.is_empty() {
+                retain_matching(todos, &|t| t.task.projects.contains(&query_params.project) || t.auto_project == query_params.project);
+            }
+            if !query_params.search.is_empty() {
+                retain_matching(todos, &|t| t.task.subject.contains(&query_params.search));
+            }
         }
         if !query_params.sort_by.is_empty() {
             match query_params.sort_by.as_ref() {
                 "create_date" => {
-                    todos_sorted.sort_unstable_by_key(|t| t.task.create_date);
+                    sort_todos_by_key(&mut todos_sorted, &|t| t.task.create_date);
                 }
                 _ => {
                     return Err(failure::err_msg(format!("invalid sort_by key: '{}'", query_params.sort_by)));
@@ -194,11 +404,23 @@ pub fn render(
         }
     }
 
+    let mut rows = vec![];
+    flatten_todos(&todos_sorted, 0, &mut rows);
+    let mut upcoming_rows = vec![];
+    flatten_todos(&upcoming, 0, &mut upcoming_rows);
+
     let hello = HelloTemplate {
         todos_count: cached_data.todos_count,
+        upcoming_count,
+        show_future: query_params.show_future,
         local_files: &cached_data.local_files,
-        todos: &todos_sorted,
+        todos: &rows,
+        upcoming: &upcoming_rows,
         deadlines: &cached_data.deadlines,
+        diagnostics: &cached_data.diagnostics,
+        orphaned_timers: &cached_data.orphaned_timers,
+        project_time: &cached_data.project_time,
+        file_time: &cached_data.file_time,
         render_opts: &RenderOpts { show_priority_text_label: false },
     };
 
@@ -211,4 +433,34 @@ mod tests {
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn upcoming_tasks_get_their_own_list() {
+        use std::str::FromStr;
+        use homepage_data::TaskWithContext;
+        use homepage_data::todo::Task;
+
+        let todo = |line: &str| TaskWithContext::new("todo.txt", 0, line, Task::from_str(line).unwrap(), "");
+        let mut trip = todo("plan trip");
+        trip.subtasks.push(todo("pack t:2999-01-01"));
+        let mut todos = vec![trip, todo("renew passport t:2998-01-01"), todo("x archived t:2999-01-01")];
+
+        let upcoming = ::take_upcoming(&mut todos);
+        let subjects = |todos: &[TaskWithContext]| todos.iter().map(|t| t.task.subject.clone()).collect::<Vec<_>>();
+        assert_eq!(subjects(&upcoming), vec!["renew passport", "pack"]);
+        assert_eq!(subjects(&todos), vec!["plan trip", "archived"]);
+        assert!(todos[0].subtasks.is_empty());
+    }
+
+    #[test]
+    fn subject_html() {
+        let html = ::filters::subject_html(&"call @Bob <re> +Work, see http://a.com/+x@y. #q2").unwrap();
+        assert_eq!(html, concat!(
+            r#"call <a class="todo-context" href="?context=bob">@Bob</a> &lt;re&gt; "#,
+            r#"<a class="todo-project" href="?project=work">+Work</a>, see "#,
+            r#"<a href="http://a.com/+x@y">http://a.com/+x@y</a>. <span class="todo-hashtag">#q2</span>"#));
+
+        let html = ::filters::subject_html(&"see javascript://%0Aalert(1) or HTTPS://a.com").unwrap();
+        assert_eq!(html, r#"see javascript://%0Aalert(1) or <a href="HTTPS://a.com">HTTPS://a.com</a>"#);
+    }
 }
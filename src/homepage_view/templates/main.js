@@ -9,7 +9,7 @@ function removeElement(array, elem) {
 
 const activeRequests = [];
 
-function postJSON(url, data, cb) {
+function postJSON(url, data, cb, errorCb) {
   console.log("POST " + url + " " + JSON.stringify(data));
   function reqListener() {
     console.log(this.response);
@@ -36,6 +36,8 @@ function postJSON(url, data, cb) {
             cb(res);
     } else {
       showNotification(this.responseText || "An error occurred.");
+      if (errorCb)
+        errorCb(this);
     }
   };
 
@@ -49,8 +51,67 @@ function archiveFinishedTasks() {
   });
 }
 
-function markTodo(hash, completed, cb) {
-  postJSON("/todos", { hash: hash, completed: completed }, cb);
+function undo() {
+  postJSON("/actions/undo", {}, function(res) {
+    sessionStorage.setItem("notification", "Undid " + res.description + ". Press U to redo.");
+    location.reload();
+  });
+}
+
+function redo() {
+  postJSON("/actions/redo", {}, function(res) {
+    sessionStorage.setItem("notification", "Redid " + res.description + ".");
+    location.reload();
+  });
+}
+
+function taskId(node) {
+  return {
+    file: node.dataset.file,
+    line: parseInt(node.dataset.line, 10),
+    tag: node.dataset.tag,
+  };
+}
+
+function markTodo(node, completed, cb, errorCb) {
+  postJSON("/todos", { id: taskId(node), version: node.value, completed: completed }, cb, errorCb);
+}
+
+function updateBlocked(todo) {
+  const selector = 'input[data-file="' + CSS.escape(todo.id.file) + '"][data-line="' + todo.id.line + '"]';
+  const node = document.querySelector(selector);
+  if (!node)
+    return;
+  const item = node.parentElement;
+  item.classList.toggle("todo-blocked", todo.blocked_by.length > 0);
+  item.getElementsByClassName("blocked-by")[0].innerText =
+    todo.blocked_by.length > 0 ? "waiting on " + todo.blocked_by.join(", ") : "";
+}
+
+function offerParent(parent) {
+  const selector = 'input[data-file="' + CSS.escape(parent.id.file) + '"][data-line="' + parent.id.line + '"]';
+  const parentNode = document.querySelector(selector);
+  if (parentNode) {
+    parentNode.click();
+  } else {
+    postJSON("/todos", { id: parent.id, version: parent.version, completed: true });
+  }
+}
+
+function toggleTimer(node) {
+  const item = node.parentElement;
+  const running = item.classList.contains("todo-timing");
+  const url = running ? "/todos/timer/stop" : "/todos/timer/start";
+  postJSON(url, { id: taskId(node), version: node.value }, function(res) {
+    item.classList.toggle("todo-timing", !running);
+    // starting a timer can give the task an id: tag
+    node.dataset.line = res.id.line;
+    node.dataset.tag = res.id.tag;
+    node.value = res.version;
+    if (running) {
+      item.getElementsByClassName("time-spent")[0].innerText = "spent " + res.spent;
+    }
+  });
 }
 
 function clickTodo(e) {
@@ -58,12 +119,27 @@ function clickTodo(e) {
   if (node.nodeName !== "INPUT")
     return;
 
-  const hash = node.getAttribute("value");
   const markFinished = node.checked;
-  markTodo(hash, markFinished, function(res) {
-    if (res.hash) {
-      node.value = res.hash;
+  markTodo(node, markFinished, function(res) {
+    node.dataset.line = res.id.line;
+    node.value = res.version;
+    const item = node.parentElement;
+    if (res.recurrence_html) {
+      // a recurring task was completed; show its next instance right below
+      // it, at the same depth
+      item.insertAdjacentHTML("afterend", res.recurrence_html);
+      const next = item.nextElementSibling;
+      next.classList.replace("todo-depth-0", "todo-depth-" + item.dataset.depth);
+      next.dataset.depth = item.dataset.depth;
+    }
+    res.dependents.forEach(updateBlocked);
+    if (res.parent && confirm("All of the subtasks of \"" + res.parent.task.subject + "\" are done. Mark it done too?")) {
+      offerParent(res.parent);
     }
+  }, function() {
+    // the change didn't happen; put the checkbox back the way it was
+    node.checked = !markFinished;
+    node.parentElement.classList.toggle("todo-done", node.checked);
   });
 }
 
@@ -100,6 +176,9 @@ function handleKeySequences(keyName) {
 }
 
 function onKeyPress(event) {
+  if (event.target.nodeName === "INPUT" && event.target.type === "text")
+    return; // typing a new todo
+
   const keyName = event.key;
   if (handleKeySequences(keyName) === false)
     return false;
@@ -121,6 +200,16 @@ function onKeyPress(event) {
         }
         break;
       }
+      case "s":
+      {
+        // start or stop a timer on the task
+        const elem = getFocusedElement();
+        if (elem && elem.nodeName == "INPUT") {
+          toggleTimer(elem);
+          return false;
+        }
+        break;
+      }
       case "Enter":
       {
         // If in a TODO list, enter follows the link.
@@ -140,6 +229,12 @@ function onKeyPress(event) {
       case "G":
         navigateToLast();
         return false;
+      case "u":
+        undo();
+        return false;
+      case "U":
+        redo();
+        return false;
     default:
       console.log("key: " + keyName);
   }
@@ -155,14 +250,18 @@ function showNotification(message) {
 
 document.addEventListener("DOMContentLoaded", function() {
   const todoList = document.getElementById("todo_list");
-  todoList.addEventListener("change", function(e) {
+  function changeTodo(e) {
     const classList = e.target.parentElement.classList;
     if (e.target.checked)
       classList.add("todo-done");
     else
       classList.remove("todo-done");
     clickTodo(e);
-  }, false);
+  }
+  todoList.addEventListener("change", changeTodo, false);
+  const upcomingList = document.getElementById("upcoming_list");
+  if (upcomingList)
+    upcomingList.addEventListener("change", changeTodo, false);
 
   window.addEventListener("beforeunload", function (e) {
     if (activeRequests.length === 0)
@@ -175,11 +274,47 @@ document.addEventListener("DOMContentLoaded", function() {
 
   document.addEventListener('keypress', onKeyPress);
 
+  document.addEventListener("change", function(e) {
+    if (!e.target.classList.contains("directory-item"))
+      return;
+    const item = e.target.parentElement;
+    postJSON("/directories/done", { source: e.target.dataset.source, name: e.target.dataset.name }, function(res) {
+      item.parentElement.removeChild(item);
+    }, function() {
+      e.target.checked = false;
+    });
+  });
+
+  document.addEventListener("click", function(e) {
+    if (!e.target.classList.contains("discard-timer"))
+      return;
+    const item = e.target.parentElement;
+    postJSON("/todos/timer/discard", { id: taskId(e.target), version: e.target.value }, function(res) {
+      item.parentElement.removeChild(item);
+    });
+  });
+
+  const addForm = document.getElementById("add_todo");
+  addForm.addEventListener("submit", function(e) {
+    e.preventDefault();
+    postJSON("/todos/add", { file: addForm.file.value, text: addForm.text.value }, function(res) {
+      todoList.insertAdjacentHTML("beforeend", res.html);
+      addForm.text.value = "";
+    });
+  });
+
   notification = document.getElementsByClassName("notification")[0];
   const deleteButton = notification.getElementsByClassName("delete")[0];
   deleteButton.addEventListener("click", function() {
       notification.style.display = "none";
   });
+
+  // left by undo and redo, which reload the page
+  const message = sessionStorage.getItem("notification");
+  if (message) {
+    sessionStorage.removeItem("notification");
+    showNotification(message);
+  }
 });
 
 function _navigate(fn) {
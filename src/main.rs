@@ -5,17 +5,20 @@ extern crate mime;
 extern crate serde;
 extern crate serde_json;
 #[macro_use] extern crate serde_derive;
-extern crate failure;
+#[macro_use] extern crate failure;
 extern crate actix_web;
 extern crate actix;
 extern crate clap;
+extern crate chrono;
+extern crate shellexpand;
 
 extern crate homepage_data;
 extern crate homepage_view;
 
 mod routes;
+mod commands;
 
-use clap::{Arg, App};
+use clap::{Arg, App, SubCommand};
 
 pub fn main() {
     let matches = App::new("homepage")
@@ -28,8 +31,54 @@ pub fn main() {
                                .value_name("PORT")
                                .help("Sets a custom port to use")
                                .takes_value(true))
+                          .arg(Arg::with_name("config")
+                               .long("config")
+                               .value_name("FILE")
+                               .env("ENCASA_CONFIG")
+                               .help("Uses this config file instead of looking for one")
+                               .takes_value(true))
+                          .subcommand(SubCommand::with_name("check-config")
+                               .about("Checks the config file and prints any problems with it"))
+                          .subcommand(SubCommand::with_name("backups")
+                               .about("Lists, shows and restores backups of todo files")
+                               .subcommand(SubCommand::with_name("list")
+                                    .about("Lists backups, newest first")
+                                    .arg(Arg::with_name("file")
+                                         .long("file")
+                                         .value_name("PATH")
+                                         .help("Only lists backups of this file")
+                                         .takes_value(true)))
+                               .subcommand(SubCommand::with_name("show")
+                                    .about("Prints the contents of a backup")
+                                    .arg(Arg::with_name("id").required(true)))
+                               .subcommand(SubCommand::with_name("restore")
+                                    .about("Puts a backup back in place of the file it was taken from")
+                                    .arg(Arg::with_name("id").required(true))))
                           .get_matches();
 
+    if let Some(config) = matches.value_of("config") {
+        homepage_data::config::set_path(config);
+    }
+
+    let result = match matches.subcommand() {
+        ("backups", Some(m)) => Some(commands::backups(m)),
+        ("check-config", Some(_)) => Some(commands::check_config()),
+        _ => None,
+    };
+    if let Some(result) = result {
+        if let Err(e) = result {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // refuse to start with a config we'd only partly understand
+    if let Err(e) = homepage_data::config::validate() {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+
     // Gets a value for config if supplied by user, or defaults to "default.conf"
     let port_str = matches.value_of("port").unwrap_or("7878");
     println!("Value for config: {}", port_str);
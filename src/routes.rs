@@ -3,12 +3,18 @@ use actix_web::{pred, http, server, App, Query, HttpResponse,
     Json, Path, middleware, Error, HttpRequest};
 use actix_web::http::Method;
 use failure;
-use homepage_view::{render, SearchParams};
+use homepage_view::{render, render_todo_item, render_backups, render_activity, SearchParams};
 use env_logger;
 use std;
 
 use homepage_data::{update_data, mark_todo_completed, archive_finished_tasks,
-    update_deadlines};
+    update_deadlines, add_todo, edit_todo, complete_directory_item, start_timer, stop_timer, discard_timer, backup_store, undo_last_change, redo_last_change,
+    TaskWithContext};
+use homepage_data::identity::{TaskId, TodoError};
+use homepage_data::backups::BackupNotFound;
+use homepage_data::directory::ItemNotFound;
+use homepage_data::store;
+use homepage_data::journal::{Entry, JournalError};
 
 fn _render_index(files_to_include: &Vec<String>, search_params: &SearchParams) -> Result<HttpResponse, failure::Error> {
     let cached_data = update_data(files_to_include)?;
@@ -21,6 +27,11 @@ fn update_deadlines_route(_info: Path<()>) -> Result<HttpResponse, failure::Erro
     _render_index(&vec![], &SearchParams::default())
 }
 
+fn diagnostics(_info: Path<()>) -> Result<HttpResponse, failure::Error> {
+    let cached_data = update_data(&vec![])?;
+    Ok(HttpResponse::Ok().json(cached_data.diagnostics))
+}
+
 fn index(query: Query<IndexQuery>) -> Result<HttpResponse, failure::Error> {
     let mut files_to_include = vec![];
     if !query.file.is_empty() {
@@ -31,13 +42,59 @@ fn index(query: Query<IndexQuery>) -> Result<HttpResponse, failure::Error> {
 
 #[derive(Deserialize, Serialize, Debug)]
 struct TodosPost {
-    hash: String,
+    id: TaskId,
+    version: String,
     completed: bool,
 }
 
 #[derive(Serialize)]
 struct TodosPostResponse {
-    hash: String,
+    id: TaskId,
+    version: String,
+    recurrence_html: Option<String>,
+    /// A parent task whose subtasks are now all finished.
+    parent: Option<TaskWithContext>,
+    /// Tasks that depend on this one, and whether they're still blocked.
+    dependents: Vec<TaskWithContext>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TodosAdd {
+    /// The expanded path of the todo file to add to.
+    file: String,
+    text: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct TodosEdit {
+    id: TaskId,
+    version: String,
+    text: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct TimerPost {
+    id: TaskId,
+    version: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct DirectoryDone {
+    /// The expanded path of the directory.
+    source: String,
+    name: String,
+}
+
+#[derive(Serialize)]
+struct DirectoryDoneResponse {
+    moved_to: String,
+}
+
+#[derive(Serialize)]
+struct TodoResponse {
+    id: TaskId,
+    version: String,
+    html: String,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -46,11 +103,81 @@ pub struct ArchiveFinishedResponse {
 }
 
 fn post_todos(data: Json<TodosPost>) -> Result<HttpResponse, failure::Error> {
-    let new_hash = mark_todo_completed(&data.hash, data.completed)?;
-    if new_hash.is_empty() {
-        Ok(HttpResponse::NotFound().body("hash not found"))
-    } else {
-        Ok(HttpResponse::Ok().json(TodosPostResponse { hash: new_hash }))
+    let update = match mark_todo_completed(&data.id, &data.version, data.completed) {
+        Ok(update) => update,
+        Err(e) => return todo_error_response(e),
+    };
+    let recurrence_html = match update.recurrence {
+        Some(ref todo) => Some(render_todo_item(todo)?),
+        None => None,
+    };
+    Ok(HttpResponse::Ok().json(TodosPostResponse {
+        id: update.id,
+        version: update.version,
+        recurrence_html,
+        parent: update.parent,
+        dependents: update.dependents,
+    }))
+}
+
+fn todo_response(todo: TaskWithContext) -> Result<HttpResponse, failure::Error> {
+    let html = render_todo_item(&todo)?;
+    Ok(HttpResponse::Ok().json(TodoResponse { id: todo.id, version: todo.version, html }))
+}
+
+fn post_todos_add(data: Json<TodosAdd>) -> Result<HttpResponse, failure::Error> {
+    match add_todo(&data.file, &data.text) {
+        Ok(todo) => todo_response(todo),
+        Err(e) => todo_error_response(e),
+    }
+}
+
+fn post_todos_edit(data: Json<TodosEdit>) -> Result<HttpResponse, failure::Error> {
+    match edit_todo(&data.id, &data.version, &data.text) {
+        Ok(todo) => todo_response(todo),
+        Err(e) => todo_error_response(e),
+    }
+}
+
+fn post_timer_start(data: Json<TimerPost>) -> Result<HttpResponse, failure::Error> {
+    match start_timer(&data.id, &data.version) {
+        Ok(update) => Ok(HttpResponse::Ok().json(update)),
+        Err(e) => todo_error_response(e),
+    }
+}
+
+fn post_timer_stop(data: Json<TimerPost>) -> Result<HttpResponse, failure::Error> {
+    match stop_timer(&data.id, &data.version) {
+        Ok(update) => Ok(HttpResponse::Ok().json(update)),
+        Err(e) => todo_error_response(e),
+    }
+}
+
+fn post_timer_discard(data: Json<TimerPost>) -> Result<HttpResponse, failure::Error> {
+    match discard_timer(&data.id, &data.version) {
+        Ok(()) => Ok(HttpResponse::Ok().json(data.into_inner())),
+        Err(e) => todo_error_response(e),
+    }
+}
+
+fn post_directory_done(data: Json<DirectoryDone>) -> Result<HttpResponse, failure::Error> {
+    match complete_directory_item(&data.source, &data.name) {
+        Ok(path) => Ok(HttpResponse::Ok().json(DirectoryDoneResponse { moved_to: path.to_string_lossy().to_string() })),
+        Err(e) => match e.downcast::<ItemNotFound>() {
+            Ok(e) => Ok(HttpResponse::NotFound().body(format!("{}", e))),
+            Err(e) => Err(e),
+        },
+    }
+}
+
+/// Turns a missing or concurrently edited task into a 404 or 409 instead of
+/// a generic server error, and an invalid one into a 400.
+fn todo_error_response(e: failure::Error) -> Result<HttpResponse, failure::Error> {
+    match e.downcast::<TodoError>() {
+        Ok(e @ TodoError::NotFound { .. }) => Ok(HttpResponse::NotFound().body(format!("{}", e))),
+        Ok(e @ TodoError::Conflict { .. }) => Ok(HttpResponse::Conflict().body(format!("{}", e))),
+        Ok(e @ TodoError::Invalid { .. }) => Ok(HttpResponse::BadRequest().body(format!("{}", e))),
+        Err(e) => Err(e),
     }
 }
 
@@ -59,6 +186,68 @@ fn archive_finished(_info: Path<()>) -> Result<HttpResponse, failure::Error> {
     Ok(HttpResponse::Ok().json(ArchiveFinishedResponse { num_archived }))
 }
 
+#[derive(Serialize)]
+struct UndoResponse {
+    description: String,
+}
+
+fn undo(_info: Path<()>) -> Result<HttpResponse, failure::Error> {
+    journal_response(undo_last_change())
+}
+
+fn redo(_info: Path<()>) -> Result<HttpResponse, failure::Error> {
+    journal_response(redo_last_change())
+}
+
+/// Turns having nothing to undo into a 400, and a file that was changed
+/// since into a 409.
+fn journal_response(result: Result<Entry, failure::Error>) -> Result<HttpResponse, failure::Error> {
+    match result {
+        Ok(entry) => Ok(HttpResponse::Ok().json(UndoResponse { description: entry.describe() })),
+        Err(e) => match e.downcast::<JournalError>() {
+            Ok(e @ JournalError::Changed { .. }) => Ok(HttpResponse::Conflict().body(format!("{}", e))),
+            Ok(e) => Ok(HttpResponse::BadRequest().body(format!("{}", e))),
+            Err(e) => Err(e),
+        },
+    }
+}
+
+fn activity(_info: Path<()>) -> Result<HttpResponse, failure::Error> {
+    let cached_data = update_data(&vec![])?;
+    let html = render_activity(&cached_data.local_files)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+fn backups(_info: Path<()>) -> Result<HttpResponse, failure::Error> {
+    let html = render_backups(&backup_store().list()?, None)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+fn show_backup(id: Path<String>) -> Result<HttpResponse, failure::Error> {
+    let store = backup_store();
+    let backup = match store.get(&id) {
+        Ok(backup) => backup,
+        Err(e) => return backup_error_response(e),
+    };
+    let contents = store.contents(&backup)?;
+    let html = render_backups(&store.list()?, Some((&backup, &contents)))?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+fn restore_backup(id: Path<String>) -> Result<HttpResponse, failure::Error> {
+    match backup_store().restore(&id) {
+        Ok(_) => Ok(HttpResponse::SeeOther().header(http::header::LOCATION, "/backups").finish()),
+        Err(e) => backup_error_response(e),
+    }
+}
+
+fn backup_error_response(e: failure::Error) -> Result<HttpResponse, failure::Error> {
+    match e.downcast::<BackupNotFound>() {
+        Ok(e) => Ok(HttpResponse::NotFound().body(format!("{}", e))),
+        Err(e) => Err(e),
+    }
+}
+
 #[derive(Deserialize)]
 pub struct IndexQuery {
     #[serde(default)] pub context: String,
@@ -66,6 +255,7 @@ pub struct IndexQuery {
     #[serde(default)] pub search: String,
     #[serde(default)] pub sort_by: String,
     #[serde(default)] pub file: String,
+    #[serde(default)] pub show_future: String,
 }
 
 impl IndexQuery {
@@ -75,6 +265,7 @@ impl IndexQuery {
             project: self.project.clone(),
             search: self.search.clone(),
             sort_by: self.sort_by.clone(),
+            show_future: self.show_future == "1" || self.show_future == "true",
         }
     }
 }
@@ -88,6 +279,11 @@ pub fn run_server(port_str: &str) {
     std::env::set_var("RUST_LOG", "actix_web=info");
     env_logger::init();
 
+    if let Err(e) = store::watch() {
+        // requests still work, they just check every file first
+        eprintln!("could not watch for changes: {}", e);
+    }
+
     let sys = actix::System::new("encasa-dev-server");
     let addr = format!("127.0.0.1:{}", port_str);
 
@@ -101,8 +297,21 @@ pub fn run_server(port_str: &str) {
                     //.limit(4096); // <- limit size of the payload
                     ;
             })
+            .route("/todos/add", http::Method::POST, post_todos_add)
+            .route("/todos/edit", http::Method::POST, post_todos_edit)
+            .route("/todos/timer/start", http::Method::POST, post_timer_start)
+            .route("/todos/timer/stop", http::Method::POST, post_timer_stop)
+            .route("/todos/timer/discard", http::Method::POST, post_timer_discard)
+            .route("/directories/done", http::Method::POST, post_directory_done)
             .route("/actions/archive_finished", http::Method::POST, archive_finished)
+            .route("/actions/undo", http::Method::POST, undo)
+            .route("/actions/redo", http::Method::POST, redo)
             .route("/update_deadlines", http::Method::GET, update_deadlines_route)
+            .route("/diagnostics", http::Method::GET, diagnostics)
+            .route("/activity", http::Method::GET, activity)
+            .route("/backups", http::Method::GET, backups)
+            .route("/backups/{id}", http::Method::GET, show_backup)
+            .route("/backups/{id}/restore", http::Method::POST, restore_backup)
             .route("/", http::Method::GET, index)
             .default_resource(|r| {
                 // 404 for GET request